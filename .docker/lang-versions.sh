@@ -6,6 +6,11 @@ echo "Python version: "
 echo "-------------------------"
 echo
 
+echo "----  PyPy version:  ----"
+/usr/bin/pypy3 --version
+echo "-------------------------"
+echo
+
 echo "----  GCC version:   ----"
 /usr/bin/gcc --version
 echo "-------------------------"
@@ -34,3 +39,23 @@ echo
 echo "---- OCaml version: ----"
 /usr/bin/ocamlopt -v
 echo "-------------------------"
+echo
+
+echo "---- Kotlin version: ----"
+/usr/bin/kotlinc -version
+echo "-------------------------"
+echo
+
+echo "----  Mono version:  ----"
+/usr/bin/mono --version
+echo "-------------------------"
+echo
+
+echo "----  Node version:  ----"
+/usr/bin/node --version
+echo "-------------------------"
+echo
+
+echo "----  GHC version:   ----"
+/usr/bin/ghc --version
+echo "-------------------------"
@@ -1,4 +1,7 @@
+use crate::isolate::IsolateLimits;
+use crate::util::general::random_bytes;
 use std::cmp::min;
+use std::str::FromStr;
 use std::{env, fs};
 use tokio::sync::{OnceCell, SetError};
 
@@ -14,11 +17,31 @@ pub struct SystemEnvironment {
 pub struct Environment {
     pub force_debug_logs: bool,
     pub max_evaluations: u8,
+    pub max_parallel_testcases: u8,
+    pub cpu_cores: Option<Vec<usize>>,
     pub redis_url: String,
     pub redis_queue_key: String,
+    pub redis_dead_letter_key: String,
     pub run_with_cgroups: bool,
     pub run_with_quotas: bool,
     pub exit_on_empty_queue: bool,
+    pub shutdown_timeout_seconds: u64,
+    pub reliable_queue: bool,
+    pub worker_id: String,
+    pub compilation_cache_dir: String,
+    pub compilation_cache_max_size_mb: u64,
+    pub cache_submission_compilations: bool,
+    pub language_registry_file: Option<String>,
+    // defaults for compilers and checkers, languages and evaluations can override them
+    pub compile_limits: IsolateLimits,
+    pub checker_limits: IsolateLimits,
+    // KiB, for submissions, evaluations can override it
+    pub output_limit_kb: u32,
+    // without cgroups, crashes with at least this much of the memory limit used count as MLE
+    pub memory_limit_rss_threshold_percent: u8,
+    // caps for output and stderr in results, evaluations can override them
+    pub result_output_max_bytes: usize,
+    pub result_error_max_bytes: usize,
 
     pub system_environment: SystemEnvironment,
 }
@@ -42,9 +65,25 @@ impl Environment {
                 .unwrap_or("2".to_string())
                 .parse::<u8>()
                 .expect("EVALUATOR_MAX_EVALUATIONS must be a number"),
+            max_parallel_testcases: env::var("EVALUATOR_MAX_PARALLEL_TESTCASES")
+                .unwrap_or("1".to_string())
+                .parse::<u8>()
+                .expect("EVALUATOR_MAX_PARALLEL_TESTCASES must be a number"),
+            cpu_cores: env::var("EVALUATOR_CPU_CORES").ok().map(|cores| {
+                cores
+                    .split(',')
+                    .map(|core| {
+                        core.trim()
+                            .parse::<usize>()
+                            .expect("EVALUATOR_CPU_CORES must be a list of numbers")
+                    })
+                    .collect()
+            }),
             redis_url: env::var("REDIS_URL").unwrap_or("redis://localhost:6379".to_string()),
             redis_queue_key: env::var("REDIS_QUEUE_KEY")
                 .unwrap_or("evaluator_msg_queue".to_string()),
+            redis_dead_letter_key: env::var("REDIS_DEAD_LETTER_KEY")
+                .unwrap_or("evaluator_dead_letter_queue".to_string()),
             run_with_cgroups: env::var("RUN_WITH_CGROUPS")
                 .unwrap_or("true".to_string())
                 .parse::<bool>()
@@ -57,12 +96,52 @@ impl Environment {
                 .unwrap_or("false".to_string())
                 .parse::<bool>()
                 .expect("EXIT_ON_EMPTY_QUEUE must be a boolean"),
+            shutdown_timeout_seconds: env::var("SHUTDOWN_TIMEOUT_SECONDS")
+                .unwrap_or("25".to_string())
+                .parse::<u64>()
+                .expect("SHUTDOWN_TIMEOUT_SECONDS must be a number"),
+            reliable_queue: env::var("RELIABLE_QUEUE")
+                .unwrap_or("false".to_string())
+                .parse::<bool>()
+                .expect("RELIABLE_QUEUE must be a boolean"),
+            worker_id: env::var("WORKER_ID")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or_else(|_| random_bytes(8)),
+            compilation_cache_dir: env::var("COMPILATION_CACHE_DIR")
+                .unwrap_or("/tmp/.compilation-cache".to_string()),
+            compilation_cache_max_size_mb: env::var("COMPILATION_CACHE_MAX_SIZE_MB")
+                .unwrap_or("512".to_string())
+                .parse::<u64>()
+                .expect("COMPILATION_CACHE_MAX_SIZE_MB must be a number"),
+            cache_submission_compilations: env::var("CACHE_SUBMISSION_COMPILATIONS")
+                .unwrap_or("false".to_string())
+                .parse::<bool>()
+                .expect("CACHE_SUBMISSION_COMPILATIONS must be a boolean"),
+            language_registry_file: env::var("LANGUAGE_REGISTRY_FILE").ok(),
+            compile_limits: read_tool_limits("COMPILE"),
+            checker_limits: read_tool_limits("CHECKER"),
+            output_limit_kb: env::var("OUTPUT_LIMIT_KB")
+                .unwrap_or("65536".to_string())
+                .parse::<u32>()
+                .expect("OUTPUT_LIMIT_KB must be a number"),
+            memory_limit_rss_threshold_percent: env::var("MEMORY_LIMIT_RSS_THRESHOLD_PERCENT")
+                .unwrap_or("80".to_string())
+                .parse::<u8>()
+                .expect("MEMORY_LIMIT_RSS_THRESHOLD_PERCENT must be a number"),
+            result_output_max_bytes: env::var("RESULT_OUTPUT_MAX_BYTES")
+                .unwrap_or("65536".to_string())
+                .parse::<usize>()
+                .expect("RESULT_OUTPUT_MAX_BYTES must be a number"),
+            result_error_max_bytes: env::var("RESULT_ERROR_MAX_BYTES")
+                .unwrap_or("65536".to_string())
+                .parse::<usize>()
+                .expect("RESULT_ERROR_MAX_BYTES must be a number"),
             system_environment,
         }
     }
 
-    pub fn init() -> Result<(), SetError<Environment>> {
-        ENVIRONMENT.set(Environment::new())
+    pub fn init() -> Result<(), Box<SetError<Environment>>> {
+        ENVIRONMENT.set(Environment::new()).map_err(Box::new)
     }
 
     pub fn get() -> &'static Environment {
@@ -73,6 +152,25 @@ impl Environment {
     }
 }
 
+// e.g. COMPILE_TIME_LIMIT_SECONDS, CHECKER_MEMORY_LIMIT_KB
+fn read_tool_limits(prefix: &str) -> IsolateLimits {
+    fn read<T: FromStr>(key: String, default: &str) -> T {
+        env::var(&key)
+            .unwrap_or(default.to_string())
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("{key} must be a number"))
+    }
+
+    IsolateLimits {
+        time_limit: read(format!("{prefix}_TIME_LIMIT_SECONDS"), "30"),
+        // 1 GiB
+        memory_limit: read(format!("{prefix}_MEMORY_LIMIT_KB"), "1048576"),
+        process_limit: read(format!("{prefix}_PROCESS_LIMIT"), "1024"),
+        // 256 MiB
+        output_limit: Some(read(format!("{prefix}_OUTPUT_LIMIT_KB"), "262144")),
+    }
+}
+
 fn read_pipe_max_size() -> anyhow::Result<usize> {
     let content = fs::read_to_string("/proc/sys/fs/pipe-max-size")?;
     let pipe_max_size: usize = content.trim().parse()?;
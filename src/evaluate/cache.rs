@@ -0,0 +1,252 @@
+use crate::environment::Environment;
+use crate::messages::{EvaluationLanguage, SourceFile};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    pub static ref COMPILATION_CACHE: Option<CompilationCache> =
+        CompilationCache::from_environment();
+}
+
+const STDERR_EXTENSION: &str = "stderr";
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total_size: u64,
+    // monotonic counter used as the LRU timestamp
+    clock: u64,
+}
+
+// on-disk cache of compiled artifacts keyed by a hash of the source,
+//  artifacts are always copied out so eviction never affects running evaluations
+pub struct CompilationCache {
+    directory: PathBuf,
+    max_size_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl CompilationCache {
+    fn from_environment() -> Option<Self> {
+        let max_size_bytes = Environment::get().compilation_cache_max_size_mb << 20;
+
+        if max_size_bytes == 0 {
+            return None;
+        }
+
+        let directory = PathBuf::from(&Environment::get().compilation_cache_dir);
+
+        match Self::new(directory, max_size_bytes) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!("Failed to initialize compilation cache, running without it: {err}");
+                None
+            }
+        }
+    }
+
+    fn new(directory: PathBuf, max_size_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+
+        // restore entries left from a previous run, oldest first
+        let mut artifacts = fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_none())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                let key = entry.file_name().to_string_lossy().to_string();
+
+                Some((key, metadata.len(), modified))
+            })
+            .collect::<Vec<_>>();
+
+        artifacts.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut index = CacheIndex::default();
+
+        for (key, size, _) in artifacts {
+            let stderr_size = fs::metadata(directory.join(&key).with_extension(STDERR_EXTENSION))
+                .map(|it| it.len())
+                .unwrap_or(0);
+
+            index.clock += 1;
+            index.total_size += size + stderr_size;
+            index.entries.insert(
+                key,
+                CacheEntry {
+                    size: size + stderr_size,
+                    last_used: index.clock,
+                },
+            );
+        }
+
+        info!(
+            "Compilation cache at {} has {} entries ({} bytes)",
+            directory.display(),
+            index.entries.len(),
+            index.total_size
+        );
+
+        let cache = CompilationCache {
+            directory,
+            max_size_bytes,
+            index: Mutex::new(index),
+        };
+
+        cache.evict(&mut cache.index.lock().expect("cache index lock"));
+
+        Ok(cache)
+    }
+
+    pub fn key(
+        language: &EvaluationLanguage,
+        code: &str,
+        files: &[SourceFile],
+        grader_files: Option<&[SourceFile]>,
+    ) -> String {
+        let mut hasher = Sha256::new();
+
+        // the language definition is part of the key so changing flags invalidates old artifacts
+        hasher.update(format!("{:?}", language.definition()));
+        hasher.update([0]);
+        hasher.update(code);
+
+        hash_files(&mut hasher, files);
+
+        // a grader changes how the submission is compiled, even without any grader files
+        if let Some(grader_files) = grader_files {
+            hasher.update(b"\0grader");
+            hash_files(&mut hasher, grader_files);
+        }
+
+        hasher.finalize().iter().fold(String::new(), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+    }
+
+    // copies the cached artifact to `destination` and returns the compiler stderr
+    pub fn get(&self, key: &str, destination: &Path) -> Option<String> {
+        let mut index = self.index.lock().expect("cache index lock");
+
+        index.clock += 1;
+        let clock = index.clock;
+
+        let entry = index.entries.get_mut(key)?;
+        entry.last_used = clock;
+
+        let artifact = self.directory.join(key);
+
+        let copied = fs::copy(&artifact, destination)
+            .and_then(|_| fs::read_to_string(artifact.with_extension(STDERR_EXTENSION)));
+
+        match copied {
+            Ok(compiler_stderr) => {
+                // keeps the LRU order across restarts
+                let _ = fs::File::options()
+                    .write(true)
+                    .open(&artifact)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+
+                debug!("compilation cache hit for {key}");
+
+                Some(compiler_stderr)
+            }
+            Err(err) => {
+                warn!("Failed to read cached compilation {key}: {err}");
+                self.remove(&mut index, key);
+
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, artifact: &Path, compiler_stderr: &str) {
+        let mut index = self.index.lock().expect("cache index lock");
+
+        if index.entries.contains_key(key) {
+            return;
+        }
+
+        let cached_artifact = self.directory.join(key);
+
+        let size = fs::copy(artifact, &cached_artifact).and_then(|size| {
+            fs::write(
+                cached_artifact.with_extension(STDERR_EXTENSION),
+                compiler_stderr,
+            )?;
+
+            Ok(size + compiler_stderr.len() as u64)
+        });
+
+        let size = match size {
+            Ok(size) => size,
+            Err(err) => {
+                warn!("Failed to cache compilation {key}: {err}");
+                let _ = fs::remove_file(&cached_artifact);
+                let _ = fs::remove_file(cached_artifact.with_extension(STDERR_EXTENSION));
+
+                return;
+            }
+        };
+
+        index.clock += 1;
+        index.total_size += size;
+
+        let last_used = index.clock;
+        index
+            .entries
+            .insert(key.to_string(), CacheEntry { size, last_used });
+
+        self.evict(&mut index);
+    }
+
+    fn evict(&self, index: &mut CacheIndex) {
+        while index.total_size > self.max_size_bytes {
+            let Some(oldest) = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            debug!("evicting {oldest} from compilation cache");
+            self.remove(index, &oldest);
+        }
+    }
+
+    fn remove(&self, index: &mut CacheIndex, key: &str) {
+        if let Some(entry) = index.entries.remove(key) {
+            index.total_size -= entry.size;
+        }
+
+        let artifact = self.directory.join(key);
+        let _ = fs::remove_file(&artifact);
+        let _ = fs::remove_file(artifact.with_extension(STDERR_EXTENSION));
+    }
+}
+
+fn hash_files(hasher: &mut Sha256, files: &[SourceFile]) {
+    for file in files {
+        hasher.update([0]);
+        hasher.update(&file.path);
+        hasher.update([0]);
+        hasher.update(&file.content);
+    }
+}
@@ -1,10 +1,13 @@
-use crate::evaluate::runnable::{
-    CompiledProcessData, JavaProcessData, PythonProcessData, RunnableProcess,
-};
+use crate::environment::Environment;
+use crate::evaluate::cache::{CompilationCache, COMPILATION_CACHE};
+use crate::evaluate::language::{CompileDefinition, GraderDefinition, LanguageDefinition};
+use crate::evaluate::runnable::{BoxFile, RunnableProcess};
+use crate::isolate::meta::ProcessStatus;
 use crate::isolate::{CommandMeta, IsolateError, IsolateLimits, IsolatedProcess, ProcessInput};
-use crate::messages::EvaluationLanguage;
+use crate::messages::{EvaluationLanguage, LimitOverrides, SourceFile};
 use crate::util;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -23,86 +26,318 @@ pub enum CompilationError {
     #[error("Failed to compile: {0}")]
     CompilationProcessError(String),
 
+    // holds the compiler output up to the timeout
+    #[error("Compilation time limit exceeded")]
+    CompilationTimeLimitExceeded(String),
+
     #[error("Tried to compile a non-compiled language: ${0}")]
     UnsupportedLanguage(EvaluationLanguage),
 
     #[error("Isolate error: {0}")]
     IsolateError(#[from] IsolateError),
+
+    #[error("Invalid source file path: {0}")]
+    InvalidSourcePath(String),
+
+    #[error("Graders aren't supported for {0}")]
+    UnsupportedGrader(EvaluationLanguage),
+
+    #[error("No grader for {0}")]
+    MissingGrader(EvaluationLanguage),
+}
+
+// what is being compiled, decides whether the result is cached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompilationTarget {
+    Submission,
+    // checkers and interactors, the same ones are compiled for every submission
+    Checker,
 }
 
 pub fn process_compilation(
     code: &str,
+    files: &[SourceFile],
+    grader: Option<&[SourceFile]>,
     language: &EvaluationLanguage,
+    limits: &LimitOverrides,
     box_id: u8,
+    target: CompilationTarget,
 ) -> Result<CompilationResult, CompilationError> {
-    match language {
-        EvaluationLanguage::Python => Ok(CompilationResult {
-            process: RunnableProcess::Python(PythonProcessData {
-                code: code.to_string(),
-            }),
-            compiler_stderr: None,
-        }),
-        _ => compile(code, language, box_id),
+    let definition = language
+        .definition()
+        .ok_or_else(|| CompilationError::UnsupportedLanguage(language.clone()))?;
+
+    let grader_files = grader.unwrap_or_default();
+
+    // files end up next to the compiler and the program, they can't escape the box
+    if let Some(file) = files
+        .iter()
+        .chain(grader_files)
+        .find(|file| !is_box_relative(&file.path))
+    {
+        return Err(CompilationError::InvalidSourcePath(file.path.clone()));
+    }
+
+    let grader_definition = match grader {
+        Some(_) => Some(
+            definition
+                .grader
+                .as_ref()
+                .ok_or_else(|| CompilationError::UnsupportedGrader(language.clone()))?,
+        ),
+        None => None,
+    };
+
+    // what the final artifact is called in the box and how it's run
+    let (artifact_name, run) = match (grader_definition, &definition.compile) {
+        (Some(grader_definition), _) => (
+            &grader_definition.link.artifact,
+            grader_definition.run.as_ref().unwrap_or(&definition.run),
+        ),
+        (None, Some(compile_definition)) => (&compile_definition.artifact, &definition.run),
+        (None, None) => {
+            return Ok(CompilationResult {
+                process: RunnableProcess::from_source(definition, code, files),
+                compiler_stderr: None,
+            })
+        }
+    };
+
+    let runtime_files = if definition.run_with_sources {
+        files.iter().chain(grader_files).cloned().collect()
+    } else {
+        vec![]
+    };
+
+    let compile_limits = Environment::get()
+        .compile_limits
+        .clone()
+        .with_overrides(&definition.compile_limits)
+        .with_overrides(limits);
+
+    let run_compilation = || match (grader_definition, &definition.compile) {
+        (Some(grader_definition), _) => compile_with_grader(
+            definition,
+            grader_definition,
+            code,
+            files,
+            grader_files,
+            &compile_limits,
+            box_id,
+        ),
+        (None, Some(compile_definition)) => compile(
+            definition,
+            compile_definition,
+            code,
+            files,
+            &compile_limits,
+            box_id,
+        ),
+        (None, None) => unreachable!("interpreted languages aren't compiled"),
+    };
+
+    let cache = COMPILATION_CACHE.as_ref().filter(|_| {
+        target == CompilationTarget::Checker || Environment::get().cache_submission_compilations
+    });
+
+    let Some(cache) = cache else {
+        let (artifact_path, compiler_stderr) = run_compilation()?;
+
+        return Ok(CompilationResult {
+            process: RunnableProcess::from_artifact(
+                artifact_path,
+                artifact_name,
+                run,
+                runtime_files,
+            ),
+            compiler_stderr: Some(compiler_stderr),
+        });
+    };
+
+    let key = CompilationCache::key(language, code, files, grader);
+    let file_path = PathBuf::from("/tmp").join(util::general::random_bytes(8));
+
+    if let Some(compiler_stderr) = cache.get(&key, &file_path) {
+        return Ok(CompilationResult {
+            process: RunnableProcess::from_artifact(file_path, artifact_name, run, runtime_files),
+            compiler_stderr: Some(compiler_stderr),
+        });
     }
+
+    let (artifact_path, compiler_stderr) = run_compilation()?;
+
+    cache.insert(&key, &artifact_path, &compiler_stderr);
+
+    Ok(CompilationResult {
+        process: RunnableProcess::from_artifact(artifact_path, artifact_name, run, runtime_files),
+        compiler_stderr: Some(compiler_stderr),
+    })
 }
 
-fn compile(
-    code: &str,
-    language: &EvaluationLanguage,
+fn is_box_relative(path: &str) -> bool {
+    let path = Path::new(path);
+
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+struct CompileStep<'a> {
+    command: CommandMeta,
+    dir_mounts: &'a [String],
+    files: Vec<BoxFile<'a>>,
+    // artifact of a previous step, copied into the box under the given name
+    previous_artifact: Option<(&'a Path, &'a str)>,
+    artifact: &'a str,
+    limits: &'a IsolateLimits,
+}
+
+// runs a compiler in the box and moves the artifact out to `artifact_path`, returns the stderr
+fn run_compile_step(
+    step: CompileStep,
     box_id: u8,
-) -> Result<CompilationResult, CompilationError> {
-    let output_file = util::general::random_bytes(8);
-    let file_path = PathBuf::from("/tmp").join(&output_file);
+    artifact_path: &Path,
+) -> Result<String, CompilationError> {
+    let mut process =
+        IsolatedProcess::new(box_id, &step.command, step.limits, step.dir_mounts.to_vec())?;
 
-    let (compiler, args, dir_mounts) = language
-        .get_compiler_command(&output_file)
-        .ok_or_else(|| CompilationError::UnsupportedLanguage(language.clone()))?;
+    process.spawn_with_hooks(ProcessInput::StdIn(vec![]), None, |isolated| {
+        if let Some((path, name)) = step.previous_artifact {
+            isolated.copy_in_box(path, name)?;
+        }
 
-    let mut process = IsolatedProcess::new(
-        box_id,
-        &CommandMeta {
-            executable: compiler.to_string(),
-            args,
-            in_path: true,
-            system: true,
-        },
-        // TODO: extract into constants
-        &IsolateLimits {
-            time_limit: 30.0,
-            memory_limit: 1 << 20, // 1 GiB
-        },
-        dir_mounts,
-    )?;
+        for file in &step.files {
+            isolated.write_in_box(file.path, file.content)?;
+        }
 
-    process.spawn(ProcessInput::StdIn(code.as_bytes().to_vec()), None)?;
+        Ok(())
+    })?;
 
     let output = process.wait_for_output()?;
 
-    let compiler_stderr = Some(String::from_utf8_lossy(&output.stderr).to_string());
+    let meta = process.load_meta()?;
+
+    let compiler_stderr = String::from_utf8_lossy(&output.stderr).to_string();
 
     if !output.status.success() {
         process.cleanup_and_reset()?;
 
-        return Err(CompilationError::CompilationProcessError(
-            String::from_utf8_lossy(&output.stderr).to_string(),
-        ));
+        if let Some(ProcessStatus::TimedOut) = meta.status {
+            return Err(CompilationError::CompilationTimeLimitExceeded(
+                compiler_stderr,
+            ));
+        }
+
+        return Err(CompilationError::CompilationProcessError(compiler_stderr));
     }
 
-    process.move_out_of_box(&output_file, &file_path)?;
+    process.move_out_of_box(step.artifact, artifact_path)?;
     process.cleanup_and_reset()?;
 
-    match language {
-        EvaluationLanguage::Java => Ok(CompilationResult {
-            process: RunnableProcess::Java(JavaProcessData {
-                built_class_name: file_path,
-            }),
-            compiler_stderr,
-        }),
-        _ => Ok(CompilationResult {
-            process: RunnableProcess::Compiled(CompiledProcessData {
-                executable_path: file_path,
-            }),
-            compiler_stderr,
-        }),
-    }
+    Ok(compiler_stderr)
+}
+
+fn submission_box_files<'a>(
+    definition: &'a LanguageDefinition,
+    code: &'a str,
+    files: &'a [SourceFile],
+) -> Vec<BoxFile<'a>> {
+    [BoxFile {
+        path: &definition.source_file,
+        content: code.as_bytes(),
+    }]
+    .into_iter()
+    .chain(files.iter().map(BoxFile::from))
+    .collect()
+}
+
+fn compile(
+    definition: &LanguageDefinition,
+    compile_definition: &CompileDefinition,
+    code: &str,
+    files: &[SourceFile],
+    limits: &IsolateLimits,
+    box_id: u8,
+) -> Result<(PathBuf, String), CompilationError> {
+    let artifact_path = PathBuf::from("/tmp").join(util::general::random_bytes(8));
+
+    let compiler_stderr = run_compile_step(
+        CompileStep {
+            command: definition.command_with_files(
+                &compile_definition.command,
+                &definition.source_file,
+                files,
+            ),
+            dir_mounts: &compile_definition.command.dir_mounts,
+            files: submission_box_files(definition, code, files),
+            previous_artifact: None,
+            artifact: &compile_definition.artifact,
+            limits,
+        },
+        box_id,
+        &artifact_path,
+    )?;
+
+    Ok((artifact_path, compiler_stderr))
+}
+
+// the submission is compiled without the grader sources (only its headers), so it can't
+//  include them, and then linked with them in a fresh box
+fn compile_with_grader(
+    definition: &LanguageDefinition,
+    grader_definition: &GraderDefinition,
+    code: &str,
+    files: &[SourceFile],
+    grader_files: &[SourceFile],
+    limits: &IsolateLimits,
+    box_id: u8,
+) -> Result<(PathBuf, String), CompilationError> {
+    let GraderDefinition { compile, link, .. } = grader_definition;
+
+    let object_path = PathBuf::from("/tmp").join(util::general::random_bytes(8));
+
+    let mut box_files = submission_box_files(definition, code, files);
+    box_files.extend(
+        grader_files
+            .iter()
+            .filter(|file| !definition.is_compiled_file(file))
+            .map(BoxFile::from),
+    );
+
+    let submission_stderr = run_compile_step(
+        CompileStep {
+            command: definition.command_with_files(
+                &compile.command,
+                &definition.source_file,
+                files,
+            ),
+            dir_mounts: &compile.command.dir_mounts,
+            files: box_files,
+            previous_artifact: None,
+            artifact: &compile.artifact,
+            limits,
+        },
+        box_id,
+        &object_path,
+    )?;
+
+    let artifact_path = PathBuf::from("/tmp").join(util::general::random_bytes(8));
+
+    let link_result = run_compile_step(
+        CompileStep {
+            command: definition.command_with_files(&link.command, &compile.artifact, grader_files),
+            dir_mounts: &link.command.dir_mounts,
+            files: grader_files.iter().map(BoxFile::from).collect(),
+            previous_artifact: Some((&object_path, &compile.artifact)),
+            artifact: &link.artifact,
+            limits,
+        },
+        box_id,
+        &artifact_path,
+    );
+
+    fs::remove_file(&object_path)?;
+
+    Ok((artifact_path, submission_stderr + &link_result?))
 }
@@ -1,115 +1,477 @@
-use crate::messages::EvaluationLanguage;
+use crate::environment::Environment;
+use crate::isolate::CommandMeta;
+use crate::messages::{EvaluationLanguage, LimitOverrides, SourceFile};
 use crate::util;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tokio::sync::OnceCell;
+use tracing::info;
 
-// argh, formatting doesn't work when the format! is called directly where it's used
-//  and format! doesn't support const strings as format strings
-#[inline]
-fn make_go_compile_script(out_file: &str) -> String {
-    format!(
-        "cat > source.go && GOCACHE=/tmp/.gocache GOFLAGS=\"-count=1\" /usr/bin/go build -o {} source.go && rm source.go",
-        out_file
-    )
+// javac wants public classes in a file of the same name, so the submission is renamed after its
+//  public class, sources are passed as script arguments
+macro_rules! java_rename_source {
+    () => {
+        "set -e
+        class=$(grep -oP '\\bpublic\\s+(?:(?:final|abstract|strictfp)\\s+)*class\\s+\\K\\w+' source.java \
+            | head -n 1)
+        if [ -n \"$class\" ] && [ \"$class\" != source ]; then
+            mv source.java \"$class.java\"
+            set -- \"${@/#source.java/$class.java}\"
+        fi
+        mkdir classes
+        /usr/bin/javac -Xlint:all -d classes \"$@\"
+        "
+    };
 }
 
-#[inline]
-fn make_java_compile_script(out_file: &str) -> String {
-    format!(
-        "cat > source.java && /usr/bin/javac -Xlint:all source.java && mv Main.class {}",
-        out_file
-    )
+// every produced class (including nested ones) ends up in the jar, the entry point is the public
+//  class if it has a main method, otherwise the first class that does
+const JAVA_COMPILE_SCRIPT: &str = concat!(
+    java_rename_source!(),
+    "main=
+    for candidate in ${class:+\"$class\"} $(cd classes && find . -name '*.class' ! -name '*$*' \
+        | sed 's|^\\./||; s|\\.class$||; s|/|.|g'); do
+        if /usr/bin/javap -cp classes -public \"$candidate\" \
+            | grep -qF 'public static void main(java.lang.String[])'; then
+            main=$candidate
+            break
+        fi
+    done
+    if [ -z \"$main\" ]; then
+        echo 'No class with a public static void main(String[] args) method found' >&2
+        exit 1
+    fi
+    /usr/bin/jar cfe program.jar \"$main\" -C classes ."
+);
+
+const JAVA_GRADER_COMPILE_SCRIPT: &str = concat!(
+    java_rename_source!(),
+    "/usr/bin/jar cf source.jar -C classes ."
+);
+const JAVA_GRADER_LINK_SCRIPT: &str = "mkdir grader \
+    && /usr/bin/javac -Xlint:all -cp source.jar -d grader \"$@\" \
+    && cp source.jar program.jar \
+    && /usr/bin/jar ufe program.jar Main -C grader .";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandDefinition {
+    // executable followed by its arguments, box-relative executables start with "./"
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub dir_mounts: Vec<String>,
+    // see CommandMeta::system
+    #[serde(default)]
+    pub system: bool,
 }
-#[inline]
-fn make_ocaml_compile_script(out_file: &str) -> String {
-    format!(
-        "cat > source.ml && /usr/bin/ocamlopt -color always -O2 -o {0} source.ml && rm source.ml",
-        out_file
-    )
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(from = "CompileDefinitionFields")]
+pub struct CompileDefinition {
+    pub command: CommandDefinition,
+    // file left in the box by the compiler, the run command gets it under the same name
+    pub artifact: String,
 }
 
+// the command fields next to the artifact, serde's flatten doesn't work with deny_unknown_fields
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompileDefinitionFields {
+    command: Vec<String>,
+    #[serde(default)]
+    dir_mounts: Vec<String>,
+    #[serde(default)]
+    system: bool,
+    artifact: String,
+}
 
-impl EvaluationLanguage {
-    // get compiler command and arguments based on language
-    pub fn get_compiler_command(
+impl From<CompileDefinitionFields> for CompileDefinition {
+    fn from(fields: CompileDefinitionFields) -> Self {
+        CompileDefinition {
+            command: CommandDefinition {
+                command: fields.command,
+                dir_mounts: fields.dir_mounts,
+                system: fields.system,
+            },
+            artifact: fields.artifact,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageDefinition {
+    // the submission is written into the box under this name
+    pub source_file: String,
+    // interpreted languages don't have a compile step and run the source file directly
+    #[serde(default)]
+    pub compile: Option<CompileDefinition>,
+    pub run: CommandDefinition,
+    // other submission files with these extensions are passed to the compiler,
+    //  in front of the source file
+    #[serde(default)]
+    pub compiled_extensions: Vec<String>,
+    // other submission files are also needed when running, e.g. imported python modules
+    #[serde(default)]
+    pub run_with_sources: bool,
+    // used instead of `compile` when the evaluation comes with a grader
+    #[serde(default)]
+    pub grader: Option<GraderDefinition>,
+    // applied over the default compilation limits, evaluations can override them further
+    #[serde(default)]
+    pub compile_limits: LimitOverrides,
+    // same, for checkers written in this language
+    #[serde(default)]
+    pub checker_limits: LimitOverrides,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraderDefinition {
+    // compiles the submission on its own (e.g. into an object file), the grader files that
+    //  aren't compiled (headers) are the only ones available to it
+    pub compile: CompileDefinition,
+    // links the compiled submission with the compiled grader files, which are passed in front of it
+    pub link: CompileDefinition,
+    // the language's run command is used if missing
+    #[serde(default)]
+    pub run: Option<CommandDefinition>,
+}
+
+#[derive(Debug)]
+pub struct LanguageRegistry {
+    languages: HashMap<EvaluationLanguage, LanguageDefinition>,
+}
+
+static LANGUAGE_REGISTRY: OnceCell<LanguageRegistry> = OnceCell::const_new();
+
+impl CommandDefinition {
+    fn new(command: &[&str]) -> Self {
+        CommandDefinition {
+            command: command.iter().map(|it| it.to_string()).collect(),
+            dir_mounts: vec![],
+            system: false,
+        }
+    }
+
+    pub fn command_meta(&self) -> CommandMeta {
+        CommandMeta {
+            executable: self.command[0].clone(),
+            args: self.command[1..].to_vec(),
+            in_path: true,
+            system: self.system,
+        }
+    }
+}
+
+impl CompileDefinition {
+    fn new(command: &[&str], artifact: &str) -> Self {
+        CompileDefinition {
+            command: CommandDefinition {
+                system: true,
+                ..CommandDefinition::new(command)
+            },
+            artifact: artifact.to_string(),
+        }
+    }
+}
+
+impl LanguageDefinition {
+    fn compiled(source_file: &str, compile: &[&str], artifact: &str, run: &[&str]) -> Self {
+        LanguageDefinition {
+            source_file: source_file.to_string(),
+            compile: Some(CompileDefinition::new(compile, artifact)),
+            run: CommandDefinition::new(run),
+            compiled_extensions: vec![],
+            run_with_sources: false,
+            grader: None,
+            compile_limits: LimitOverrides::default(),
+            checker_limits: LimitOverrides::default(),
+        }
+    }
+
+    fn with_grader(mut self, compile: CompileDefinition, link: CompileDefinition) -> Self {
+        self.grader = Some(GraderDefinition {
+            compile,
+            link,
+            run: None,
+        });
+        self
+    }
+
+    fn with_grader_run(mut self, run: &[&str]) -> Self {
+        if let Some(grader) = &mut self.grader {
+            grader.run = Some(CommandDefinition::new(run));
+        }
+        self
+    }
+
+    fn with_compiled_extensions(mut self, extensions: &[&str]) -> Self {
+        self.compiled_extensions = extensions.iter().map(|it| it.to_string()).collect();
+        self
+    }
+
+    fn with_sources_at_runtime(mut self) -> Self {
+        self.run_with_sources = true;
+        self
+    }
+
+    fn with_compile_limits(mut self, compile_limits: LimitOverrides) -> Self {
+        self.compile_limits = compile_limits;
+        self
+    }
+
+    pub fn is_compiled_file(&self, file: &SourceFile) -> bool {
+        Path::new(&file.path)
+            .extension()
+            .and_then(|it| it.to_str())
+            .is_some_and(|extension| self.compiled_extensions.iter().any(|it| it == extension))
+    }
+
+    // the command with the compiled files inserted in front of `anchor`, or at the end
+    pub fn command_with_files(
         &self,
-        out_file: &str,
-    ) -> Option<(&'static str, Vec<String>, Vec<String>)> {
-        type E = EvaluationLanguage;
-        match self {
-            E::C => Some((
-                "/usr/bin/gcc",
-                vec![
-                    "-fdiagnostics-color=always",
-                    "-std=c11",
-                    "-x",
-                    "c",
-                    "-O2",
-                    "-static",
-                    "-Wall",
-                    "-o",
-                    out_file,
-                    "-",
-                    "-lm",
-                ]
-                .into_iter()
-                .map(String::from)
-                .collect(),
-                vec![],
-            )),
-            E::Cpp => Some((
-                "/usr/bin/g++",
-                vec![
-                    "-fdiagnostics-color=always",
-                    "-std=c++17",
-                    "-x",
-                    "c++",
-                    "-O2",
-                    "-static",
-                    "-Wall",
-                    "-o",
-                    out_file,
-                    "-",
-                ]
-                .into_iter()
-                .map(String::from)
-                .collect(),
-                vec![],
-            )),
-            E::Rust => Some((
-                "/usr/bin/rustc",
-                vec![
+        command: &CommandDefinition,
+        anchor: &str,
+        files: &[SourceFile],
+    ) -> CommandMeta {
+        let compiled_files = files
+            .iter()
+            .filter(|file| self.is_compiled_file(file))
+            .map(|file| file.path.clone());
+
+        let mut command_meta = command.command_meta();
+
+        let position = command_meta
+            .args
+            .iter()
+            .position(|arg| arg == anchor)
+            .unwrap_or(command_meta.args.len());
+
+        command_meta.args.splice(position..position, compiled_files);
+
+        command_meta
+    }
+
+    fn commands_mut(&mut self) -> impl Iterator<Item = &mut CommandDefinition> {
+        let (grader_commands, grader_run) = match &mut self.grader {
+            Some(GraderDefinition { compile, link, run }) => {
+                (vec![&mut compile.command, &mut link.command], run.as_mut())
+            }
+            None => (vec![], None),
+        };
+
+        self.compile
+            .iter_mut()
+            .map(|it| &mut it.command)
+            .chain([&mut self.run])
+            .chain(grader_commands)
+            .chain(grader_run)
+    }
+
+    fn with_dir_mounts(mut self, dir_mounts: Vec<String>) -> Self {
+        for command in self.commands_mut() {
+            command.dir_mounts = dir_mounts.clone();
+        }
+
+        self
+    }
+
+    fn validate(&mut self) -> anyhow::Result<()> {
+        let commands = self.commands_mut();
+
+        for command in commands {
+            if command.command.is_empty() {
+                anyhow::bail!("command can't be empty");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// C and C++ only differ in the compiler and the standard
+fn gcc_language(compiler: &str, standard: &str, source_file: &str) -> LanguageDefinition {
+    let standard = format!("-std={standard}");
+
+    let mut compile = vec![
+        compiler,
+        "-fdiagnostics-color=always",
+        &standard,
+        "-O2",
+        "-static",
+        "-Wall",
+        "-o",
+        "program",
+        source_file,
+    ];
+
+    // with a grader, the submission is compiled into an object file and linked with it,
+    //  -r (partial linking) instead of -c so other submission files end up in the same object
+    let grader_compile = [
+        compiler,
+        "-fdiagnostics-color=always",
+        &standard,
+        "-O2",
+        "-Wall",
+        "-r",
+        "-o",
+        "source.o",
+        source_file,
+    ];
+
+    let mut grader_link = vec![
+        compiler,
+        "-fdiagnostics-color=always",
+        &standard,
+        "-O2",
+        "-static",
+        "-Wall",
+        "-o",
+        "program",
+        "source.o",
+    ];
+
+    if compiler.ends_with("gcc") {
+        compile.push("-lm");
+        grader_link.push("-lm");
+    }
+
+    let extensions: &[&str] = if compiler.ends_with("gcc") {
+        &["c"]
+    } else {
+        &["cpp", "cc", "cxx"]
+    };
+
+    LanguageDefinition::compiled(source_file, &compile, "program", &["./program"])
+        .with_compiled_extensions(extensions)
+        .with_grader(
+            CompileDefinition::new(&grader_compile, "source.o"),
+            CompileDefinition::new(&grader_link, "program"),
+        )
+}
+
+// byte-compiling catches syntax errors before any testcase runs, the checked source is the artifact
+fn python_language(interpreter: &str) -> LanguageDefinition {
+    let compile = [interpreter, "-m", "py_compile", "source.py"];
+
+    // graders are started through grader.py and import the submission as the `source` module,
+    //  which means the grader sources are in the box at runtime and the submission can read
+    //  them, only use graders that don't contain anything secret
+    LanguageDefinition::compiled(
+        "source.py",
+        &compile,
+        "source.py",
+        &[interpreter, "source.py"],
+    )
+    .with_compiled_extensions(&["py"])
+    .with_sources_at_runtime()
+    .with_grader(
+        CompileDefinition::new(&compile, "source.py"),
+        CompileDefinition::new(&[interpreter, "-m", "py_compile"], "source.py"),
+    )
+    .with_grader_run(&[interpreter, "grader.py"])
+}
+
+fn builtin_languages() -> HashMap<EvaluationLanguage, LanguageDefinition> {
+    type E = EvaluationLanguage;
+    type L = LanguageDefinition;
+
+    HashMap::from([
+        (E::C, gcc_language("/usr/bin/gcc", "c11", "source.c")),
+        (E::C17, gcc_language("/usr/bin/gcc", "c17", "source.c")),
+        (E::Cpp, gcc_language("/usr/bin/g++", "c++17", "source.cpp")),
+        (
+            E::Cpp20,
+            gcc_language("/usr/bin/g++", "c++20", "source.cpp"),
+        ),
+        (
+            E::Cpp23,
+            gcc_language("/usr/bin/g++", "c++23", "source.cpp"),
+        ),
+        (E::Python, python_language("/usr/bin/python3")),
+        (E::PyPy3, python_language("/usr/bin/pypy3")),
+        (
+            E::Rust,
+            L::compiled(
+                "source.rs",
+                &[
+                    "/usr/bin/rustc",
                     "--color=always",
                     "-C",
                     "opt-level=2",
                     "-C",
                     "target-feature=+crt-static",
                     "-o",
-                    out_file,
-                    "-",
-                ]
-                .into_iter()
-                .map(String::from)
-                .collect(),
-                vec![],
-            )),
-            E::Go => Some((
-                "/usr/bin/bash",
-                vec!["-c", &make_go_compile_script(out_file)]
-                    .into_iter()
-                    .map(String::from)
-                    .collect(),
-                vec![],
-            )),
-            E::Java => Some((
-                "/usr/bin/bash",
-                vec!["-c", &make_java_compile_script(out_file)]
-                    .into_iter()
-                    .map(String::from)
-                    .collect(),
-                util::general::ETC_JAVA_DIRECTORIES.clone(),
-            )),
-            E::GnuAsmX86Linux => Some((
-                "/usr/bin/gcc",
-                vec![
+                    "program",
+                    "source.rs",
+                ],
+                "program",
+                &["./program"],
+            ),
+        ),
+        (
+            E::Java,
+            L::compiled(
+                "source.java",
+                &[
+                    "/usr/bin/bash",
+                    "-c",
+                    JAVA_COMPILE_SCRIPT,
+                    "javac",
+                    "source.java",
+                ],
+                "program.jar",
+                &["/usr/bin/java", "-jar", "program.jar"],
+            )
+            .with_compiled_extensions(&["java"])
+            // the grader provides the Main class
+            .with_grader(
+                CompileDefinition::new(
+                    &[
+                        "/usr/bin/bash",
+                        "-c",
+                        JAVA_GRADER_COMPILE_SCRIPT,
+                        "javac",
+                        "source.java",
+                    ],
+                    "source.jar",
+                ),
+                CompileDefinition::new(
+                    &["/usr/bin/bash", "-c", JAVA_GRADER_LINK_SCRIPT, "link"],
+                    "program.jar",
+                ),
+            )
+            .with_grader_run(&["/usr/bin/java", "-jar", "program.jar"])
+            .with_dir_mounts(util::general::ETC_JAVA_DIRECTORIES.clone()),
+        ),
+        (
+            E::Go,
+            L::compiled(
+                "source.go",
+                &[
+                    "/usr/bin/env",
+                    "GOCACHE=/tmp/.gocache",
+                    "GOFLAGS=-count=1",
+                    "/usr/bin/go",
+                    "build",
+                    "-o",
+                    "program",
+                    "source.go",
+                ],
+                "program",
+                &["./program"],
+            )
+            .with_compiled_extensions(&["go"]),
+        ),
+        (
+            E::GnuAsmX86Linux,
+            L::compiled(
+                "source.s",
+                &[
+                    "/usr/bin/gcc",
                     "-fdiagnostics-color=always",
                     "-x",
                     "assembler",
@@ -117,23 +479,197 @@ impl EvaluationLanguage {
                     "-nostdlib",
                     "-no-pie",
                     "-o",
-                    out_file,
-                    "-",
-                ]
-                .into_iter()
-                .map(String::from)
-                .collect(),
-                vec![],
-            )),
-            E::OCaml => Some((
-                "/usr/bin/bash",
-                vec!["-c", &make_ocaml_compile_script(out_file)]
-                    .into_iter()
-                    .map(String::from)
-                    .collect(),
-                vec![],
-            )),
-            E::Python => None,
+                    "program",
+                    "source.s",
+                ],
+                "program",
+                &["./program"],
+            ),
+        ),
+        (
+            E::OCaml,
+            L::compiled(
+                "source.ml",
+                &[
+                    "/usr/bin/ocamlopt",
+                    "-color",
+                    "always",
+                    "-O2",
+                    "-o",
+                    "program",
+                    "source.ml",
+                ],
+                "program",
+                &["./program"],
+            )
+            .with_compiled_extensions(&["ml"]),
+        ),
+        (
+            E::Kotlin,
+            L::compiled(
+                "source.kt",
+                &[
+                    "/usr/bin/kotlinc",
+                    "source.kt",
+                    "-include-runtime",
+                    "-d",
+                    "program.jar",
+                ],
+                "program.jar",
+                &["/usr/bin/java", "-jar", "program.jar"],
+            )
+            .with_compiled_extensions(&["kt"])
+            // kotlinc alone takes several seconds to start up
+            .with_compile_limits(LimitOverrides {
+                time_limit: Some(60.0),
+                ..LimitOverrides::default()
+            })
+            .with_dir_mounts(util::general::ETC_JAVA_DIRECTORIES.clone()),
+        ),
+        (
+            E::CSharp,
+            L::compiled(
+                "source.cs",
+                &[
+                    "/usr/bin/mcs",
+                    "-optimize+",
+                    "-out:program.exe",
+                    "source.cs",
+                ],
+                "program.exe",
+                &["/usr/bin/mono", "program.exe"],
+            )
+            .with_compiled_extensions(&["cs"])
+            .with_dir_mounts(vec!["/etc/mono:maybe".to_string()]),
+        ),
+        (
+            E::JavaScript,
+            // only checks the syntax, like python
+            L::compiled(
+                "source.js",
+                &["/usr/bin/node", "--check", "source.js"],
+                "source.js",
+                &["/usr/bin/node", "source.js"],
+            )
+            .with_sources_at_runtime(),
+        ),
+        (
+            E::Haskell,
+            L::compiled(
+                "source.hs",
+                &["/usr/bin/ghc", "-O2", "-o", "program", "source.hs"],
+                "program",
+                &["./program"],
+            )
+            // ghc package database
+            .with_dir_mounts(vec!["/var/lib/ghc:maybe".to_string()]),
+        ),
+    ])
+}
+
+// the file is a map of language names to definitions, json if the extension says so, toml otherwise,
+//  names other than the built-in ones add new languages
+fn load_registry_file(
+    path: &Path,
+) -> anyhow::Result<HashMap<EvaluationLanguage, LanguageDefinition>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut languages: HashMap<EvaluationLanguage, LanguageDefinition> =
+        if path.extension().is_some_and(|it| it == "json") {
+            serde_json::from_str(&content)?
+        } else {
+            toml::from_str(&content)?
+        };
+
+    for (language, definition) in &mut languages {
+        definition
+            .validate()
+            .map_err(|err| anyhow::anyhow!("Invalid definition for {language}: {err}"))?;
+    }
+
+    Ok(languages)
+}
+
+impl LanguageRegistry {
+    pub fn init() -> anyhow::Result<()> {
+        let mut languages = builtin_languages();
+
+        if let Some(path) = &Environment::get().language_registry_file {
+            let overrides = load_registry_file(Path::new(path))?;
+
+            info!(
+                "Loaded {} language definitions from {path}",
+                overrides.len()
+            );
+
+            languages.extend(overrides);
+        }
+
+        LANGUAGE_REGISTRY.set(LanguageRegistry { languages })?;
+
+        Ok(())
+    }
+
+    pub fn get() -> &'static LanguageRegistry {
+        match LANGUAGE_REGISTRY.get() {
+            Some(registry) => registry,
+            None => panic!("Language registry not initialized"),
         }
     }
 }
+
+impl EvaluationLanguage {
+    pub fn definition(&self) -> Option<&'static LanguageDefinition> {
+        LanguageRegistry::get().languages.get(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_file_can_add_languages() {
+        let languages: HashMap<EvaluationLanguage, LanguageDefinition> = toml::from_str(
+            r#"
+            [cpp]
+            source_file = "source.cpp"
+            run = { command = ["./program"] }
+            compile = { command = ["/usr/bin/g++", "source.cpp"], artifact = "program" }
+
+            [lua]
+            source_file = "source.lua"
+            run = { command = ["/usr/bin/lua", "source.lua"] }
+            "#,
+        )
+        .unwrap();
+
+        assert!(languages.contains_key(&EvaluationLanguage::Cpp));
+        assert!(languages.contains_key(&EvaluationLanguage::Custom("lua".to_string())));
+    }
+
+    #[test]
+    fn registry_file_rejects_unknown_fields() {
+        let result = toml::from_str::<HashMap<EvaluationLanguage, LanguageDefinition>>(
+            r#"
+            [lua]
+            source_file = "source.lua"
+            run = { command = ["/usr/bin/lua", "source.lua"], dir_mount = ["/usr/lib"] }
+            "#,
+        );
+
+        assert!(result.is_err());
+
+        let result = toml::from_str::<HashMap<EvaluationLanguage, LanguageDefinition>>(
+            r#"
+            [lua]
+            source_file = "source.lua"
+            run = { command = ["/usr/bin/lua", "source.lua"] }
+            compile = { command = ["/usr/bin/luac", "source.lua"], artifact = "luac.out", sytem = true }
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+}
@@ -1,11 +1,19 @@
+mod cache;
 mod compilation;
-mod language;
+pub mod language;
 mod output;
+mod progress;
 pub mod queue_handler;
+mod result_output;
 mod runnable;
 mod types;
 
+use crate::environment::Environment;
 use crate::evaluate::compilation::CompilationError;
+use crate::evaluate::progress::ProgressReporter;
+use crate::evaluate::result_output::ResultOutputFormatter;
+use crate::isolate::meta::{ProcessMeta, ProcessStatus};
+use crate::isolate::IsolateLimits;
 use crate::messages::Evaluation;
 use serde::Serialize;
 
@@ -14,9 +22,33 @@ pub struct SuccessfulEvaluation {
     evaluation_id: u64,
     verdict: Verdict,
     max_time: u32,
+    // KiB, same as max_memory_bytes
     max_memory: u32,
+    max_memory_bytes: u64,
     testcases: Vec<TestcaseResult>,
+    subtasks: Vec<SubtaskResult>,
+    // sum of subtask scores, or the average testcase score in [0, 1] if there are no subtasks
+    score: Option<f64>,
     compiler_output: Option<String>,
+    // set when the evaluation couldn't be judged at all
+    error: Option<String>,
+}
+
+impl SuccessfulEvaluation {
+    pub fn system_error(evaluation_id: u64, error: String) -> Self {
+        SuccessfulEvaluation {
+            evaluation_id,
+            verdict: Verdict::SystemError,
+            max_time: 0,
+            max_memory: 0,
+            max_memory_bytes: 0,
+            testcases: vec![],
+            subtasks: vec![],
+            score: None,
+            compiler_output: None,
+            error: Some(error),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -24,9 +56,87 @@ pub struct TestcaseResult {
     pub id: String,
     pub verdict: Verdict,
     pub time: u32,
+    // KiB, same as memory_bytes
     pub memory: u32,
+    pub memory_bytes: u64,
     pub output: Option<String>,
     pub error: Option<String>,
+    pub exit_code: Option<i32>,
+    // signal name, e.g. "SIGSEGV"
+    pub exit_signal: Option<String>,
+    pub wall_time: u32,
+    // isolate's description of how the program ended, e.g. "Time limit exceeded"
+    pub message: Option<String>,
+    // bytes cut off from the end of output and error
+    pub output_truncated_bytes: usize,
+    pub error_truncated_bytes: usize,
+    // explanation from the checker or interactor, e.g. testlib's stderr
+    pub checker_message: Option<String>,
+}
+
+impl TestcaseResult {
+    // a testcase whose program didn't run, or whose run couldn't be used
+    pub fn without_run(id: &str, verdict: Verdict, error: Option<String>) -> Self {
+        TestcaseResult {
+            id: id.to_string(),
+            verdict,
+            time: 0,
+            memory: 0,
+            memory_bytes: 0,
+            output: None,
+            error,
+            exit_code: None,
+            exit_signal: None,
+            wall_time: 0,
+            message: None,
+            output_truncated_bytes: 0,
+            error_truncated_bytes: 0,
+            checker_message: None,
+        }
+    }
+
+    pub fn skipped(id: &str) -> Self {
+        Self::without_run(id, Verdict::Skipped, None)
+    }
+
+    // resources and exit status of the program's run, output and error are added separately
+    pub fn from_meta(id: &str, verdict: Verdict, meta: &ProcessMeta) -> Self {
+        TestcaseResult {
+            time: meta.time_ms,
+            memory: meta.memory_kb,
+            memory_bytes: meta.memory_bytes(),
+            exit_code: meta.exit_code,
+            exit_signal: meta.exit_signal_name(),
+            wall_time: meta.wall_time_ms,
+            message: meta.message.clone(),
+            ..Self::without_run(id, verdict, None)
+        }
+    }
+
+    pub fn with_output(mut self, formatter: &ResultOutputFormatter, stdout: &[u8]) -> Self {
+        (self.output, self.output_truncated_bytes) = formatter.output(&self.verdict, stdout);
+        self
+    }
+
+    pub fn with_checker_message(mut self, message: Option<String>) -> Self {
+        self.checker_message = message;
+        self
+    }
+
+    pub fn with_error(mut self, formatter: &ResultOutputFormatter, stderr: &[u8]) -> Self {
+        let (error, truncated_bytes) = formatter.error(stderr);
+
+        self.error = Some(error);
+        self.error_truncated_bytes = truncated_bytes;
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubtaskResult {
+    pub id: u32,
+    pub verdict: Verdict,
+    pub score: f64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -38,33 +148,122 @@ pub enum Verdict {
     WrongAnswer,
     #[serde(rename = "custom")]
     Custom(String),
+    // score in (0, 1) awarded by a checker
+    #[serde(rename = "partial")]
+    Partial(f64),
     #[serde(rename = "time_limit_exceeded")]
     TimeLimitExceeded,
     #[serde(rename = "memory_limit_exceeded")]
     MemoryLimitExceeded,
+    #[serde(rename = "output_limit_exceeded")]
+    OutputLimitExceeded,
+    // wall clock limit exceeded while barely using the cpu, e.g. waiting for input
+    #[serde(rename = "idleness_limit_exceeded")]
+    IdlenessLimitExceeded,
     #[serde(rename = "runtime_error")]
     RuntimeError,
+    // signal name, e.g. "SIGSEGV"
+    #[serde(rename = "killed_by_signal")]
+    KilledBySignal(String),
+    #[serde(rename = "non_zero_exit_code")]
+    NonZeroExitCode(i32),
     #[serde(rename = "judging_error")]
     JudgingError,
     #[serde(rename = "system_error")]
     SystemError,
     #[serde(rename = "compilation_error")]
     CompilationError(String),
+    #[serde(rename = "compilation_time_limit_exceeded")]
+    CompilationTimeLimitExceeded,
     #[serde(rename = "skipped")]
     Skipped,
 }
 
+impl Verdict {
+    // passing verdicts don't cause the following testcases to be skipped
+    pub fn is_passing(&self) -> bool {
+        matches!(
+            self,
+            Verdict::Accepted | Verdict::Custom(_) | Verdict::Partial(_)
+        )
+    }
+
+    pub fn score(&self) -> f64 {
+        match self {
+            Verdict::Accepted | Verdict::Custom(_) => 1.0,
+            Verdict::Partial(score) => *score,
+            _ => 0.0,
+        }
+    }
+}
+
+// verdict of a program that didn't finish successfully
+pub fn failure_verdict(meta: &ProcessMeta, limits: &IsolateLimits) -> Verdict {
+    if meta.output_limit_exceeded {
+        return Verdict::OutputLimitExceeded;
+    }
+
+    match meta.status {
+        // isolate itself failed, not the program
+        Some(ProcessStatus::SandboxError) => Verdict::SystemError,
+        // the wall clock limit is below the cpu kill point, so a busy loop can hit it too
+        Some(ProcessStatus::TimedOut)
+            if meta.timed_out_on_wall_clock()
+                && (meta.time_ms as f32) < limits.time_limit * 1000.0 =>
+        {
+            Verdict::IdlenessLimitExceeded
+        }
+        Some(ProcessStatus::TimedOut) => Verdict::TimeLimitExceeded,
+        _ if meta.cg_oom_killed => Verdict::MemoryLimitExceeded,
+        // without cgroups the limit is on address space, a failed allocation ends in a crash
+        //  with the rss somewhat below the limit
+        Some(ProcessStatus::RuntimeError | ProcessStatus::SignalExit)
+            if !Environment::get().run_with_cgroups
+                && meta.max_rss_kb.unwrap_or(0) as u64 * 100
+                    >= limits.memory_limit as u64
+                        * Environment::get().memory_limit_rss_threshold_percent as u64 =>
+        {
+            Verdict::MemoryLimitExceeded
+        }
+        Some(ProcessStatus::SignalExit) => match meta.exit_signal_name() {
+            Some(signal) => Verdict::KilledBySignal(signal),
+            None => Verdict::RuntimeError,
+        },
+        Some(ProcessStatus::RuntimeError) => match meta.exit_code {
+            Some(exit_code) if exit_code != 0 => Verdict::NonZeroExitCode(exit_code),
+            _ => Verdict::RuntimeError,
+        },
+        None => Verdict::RuntimeError,
+    }
+}
+
+pub fn average_score(testcase_results: &[TestcaseResult]) -> Option<f64> {
+    if testcase_results.is_empty() {
+        return None;
+    }
+
+    let total = testcase_results
+        .iter()
+        .map(|it| it.verdict.score())
+        .sum::<f64>();
+
+    Some(total / testcase_results.len() as f64)
+}
+
 pub fn begin_evaluation(
     evaluation: &Evaluation,
     boxes: &[u8],
+    progress: &ProgressReporter,
 ) -> Result<SuccessfulEvaluation, CompilationError> {
     match evaluation {
-        Evaluation::Batch(batch_evaluation) => types::batch::evaluate(batch_evaluation, boxes[0]),
+        Evaluation::Batch(batch_evaluation) => {
+            types::batch::evaluate(batch_evaluation, boxes, progress)
+        }
         Evaluation::OutputOnly(output_only_evaluation) => {
-            types::output_only::evaluate(output_only_evaluation, boxes[0])
+            types::output_only::evaluate(output_only_evaluation, boxes[0], progress)
         }
         Evaluation::Interactive(interactive_evaluation) => {
-            types::interactive::evaluate(interactive_evaluation, boxes[0], boxes[1])
+            types::interactive::evaluate(interactive_evaluation, boxes[0], boxes[1], progress)
         }
     }
 }
@@ -1,8 +1,12 @@
-use crate::evaluate::compilation::{process_compilation, CompilationError};
-use crate::evaluate::runnable::{ProcessRunError, RunnableProcess};
+use crate::environment::Environment;
+use crate::evaluate::compilation::{process_compilation, CompilationError, CompilationTarget};
+use crate::evaluate::runnable::{
+    BoxFile, ProcessRunError, ProcessRunResult, RunExtras, RunnableProcess,
+};
 use crate::evaluate::Verdict;
+use crate::isolate::meta::{ProcessMeta, ProcessStatus};
 use crate::isolate::{IsolateLimits, ProcessInput};
-use crate::messages::{CheckerData, Testcase};
+use crate::messages::{CheckerData, CheckerProtocol, EvaluationLanguage, LimitOverrides, Testcase};
 use crate::util::general::random_bytes;
 use thiserror::Error;
 
@@ -30,14 +34,57 @@ impl From<&CheckerError> for Verdict {
 }
 
 pub enum OutputChecker {
-    Script(RunnableProcess),
+    Script(RunnableProcess, IsolateLimits),
+    Testlib(RunnableProcess, IsolateLimits),
     Raw,
 }
 
+// file names in the checker box, passed to testlib programs in this order
+pub const TESTLIB_INPUT_FILE: &str = "input.txt";
+pub const TESTLIB_OUTPUT_FILE: &str = "output.txt";
+pub const TESTLIB_ANSWER_FILE: &str = "answer.txt";
+
+// https://github.com/MikeMirzayanov/testlib/blob/master/testlib.h
+const TESTLIB_EXIT_OK: i32 = 0;
+const TESTLIB_EXIT_WRONG_ANSWER: i32 = 1;
+const TESTLIB_EXIT_PRESENTATION_ERROR: i32 = 2;
+const TESTLIB_EXIT_FAIL: i32 = 3;
+const TESTLIB_EXIT_POINTS: i32 = 7;
+
 pub enum CheckerResult {
     Accepted,
     WrongAnswer,
     Custom(String),
+    // fraction of the testcase score, in [0, 1]
+    Points(f64),
+}
+
+// checker's result together with the explanation it gave, if any
+pub struct CheckerOutcome {
+    pub result: CheckerResult,
+    pub message: Option<String>,
+}
+
+impl From<CheckerResult> for CheckerOutcome {
+    fn from(result: CheckerResult) -> Self {
+        CheckerOutcome {
+            result,
+            message: None,
+        }
+    }
+}
+
+impl From<CheckerResult> for Verdict {
+    fn from(value: CheckerResult) -> Self {
+        match value {
+            CheckerResult::Accepted => Verdict::Accepted,
+            CheckerResult::WrongAnswer => Verdict::WrongAnswer,
+            CheckerResult::Custom(message) => Verdict::Custom(message),
+            CheckerResult::Points(points) if points >= 1.0 => Verdict::Accepted,
+            CheckerResult::Points(points) if points <= 0.0 => Verdict::WrongAnswer,
+            CheckerResult::Points(points) => Verdict::Partial(points),
+        }
+    }
 }
 
 fn trim_every_line(input: &str) -> String {
@@ -56,9 +103,9 @@ impl OutputChecker {
         box_id: u8,
         output: &str,
         testcase: &Testcase,
-    ) -> Result<CheckerResult, CheckerError> {
+    ) -> Result<CheckerOutcome, CheckerError> {
         match self {
-            OutputChecker::Script(process) => {
+            OutputChecker::Script(process, limits) => {
                 let mut separator = String::from("[");
                 separator.push_str(&random_bytes(32));
                 separator.push_str("]\n");
@@ -79,11 +126,7 @@ impl OutputChecker {
                     .run(
                         box_id,
                         ProcessInput::StdIn(input.as_bytes().to_vec()),
-                        // TODO: extract into variables
-                        &IsolateLimits {
-                            time_limit: 30.0,
-                            memory_limit: 1 << 20, // 1 GiB
-                        },
+                        limits,
                         None,
                     )?
                     .output;
@@ -96,14 +139,46 @@ impl OutputChecker {
                 let text_output = text_output.trim();
 
                 // FIXME: legacy
-                CheckerResult::try_from(text_output)
+                CheckerResult::try_from(text_output).map(CheckerOutcome::from)
+            }
+            OutputChecker::Testlib(process, limits) => {
+                let extras = RunExtras {
+                    args: [TESTLIB_INPUT_FILE, TESTLIB_OUTPUT_FILE, TESTLIB_ANSWER_FILE]
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                    files: vec![
+                        BoxFile {
+                            path: TESTLIB_INPUT_FILE,
+                            content: testcase.input.as_bytes(),
+                        },
+                        BoxFile {
+                            path: TESTLIB_OUTPUT_FILE,
+                            content: output.as_bytes(),
+                        },
+                        BoxFile {
+                            path: TESTLIB_ANSWER_FILE,
+                            content: testcase.output.as_bytes(),
+                        },
+                    ],
+                };
+
+                let ProcessRunResult { output, meta } = process.run_with_extras(
+                    box_id,
+                    ProcessInput::StdIn(vec![]),
+                    limits,
+                    None,
+                    &extras,
+                )?;
+
+                CheckerResult::from_testlib(&meta, &String::from_utf8_lossy(&output.stderr))
             }
             OutputChecker::Raw => {
                 if trim_every_line(output) == trim_every_line(&testcase.output) {
-                    return Ok(CheckerResult::Accepted);
+                    return Ok(CheckerResult::Accepted.into());
                 }
 
-                Ok(CheckerResult::WrongAnswer)
+                Ok(CheckerResult::WrongAnswer.into())
             }
         }
     }
@@ -114,16 +189,94 @@ impl TryFrom<(u8, &Option<CheckerData>)> for OutputChecker {
 
     fn try_from((box_id, value): (u8, &Option<CheckerData>)) -> Result<Self, Self::Error> {
         match value {
-            Some(CheckerData { script, language }) => {
-                let compiled_checker = process_compilation(script, language, box_id)?;
+            Some(CheckerData {
+                script,
+                language,
+                protocol,
+                limits,
+            }) => {
+                let compiled_checker = process_compilation(
+                    script,
+                    &[],
+                    None,
+                    language,
+                    &LimitOverrides::default(),
+                    box_id,
+                    CompilationTarget::Checker,
+                )?;
+
+                let limits = checker_limits(language, limits);
 
-                Ok(OutputChecker::Script(compiled_checker.process))
+                match protocol {
+                    CheckerProtocol::Legacy => {
+                        Ok(OutputChecker::Script(compiled_checker.process, limits))
+                    }
+                    CheckerProtocol::Testlib => {
+                        Ok(OutputChecker::Testlib(compiled_checker.process, limits))
+                    }
+                }
             }
             _ => Ok(OutputChecker::Raw),
         }
     }
 }
 
+// defaults, then the language's overrides, then the evaluation's
+pub fn checker_limits(language: &EvaluationLanguage, overrides: &LimitOverrides) -> IsolateLimits {
+    let limits = Environment::get().checker_limits.clone();
+
+    let limits = match language.definition() {
+        Some(definition) => limits.with_overrides(&definition.checker_limits),
+        None => limits,
+    };
+
+    limits.with_overrides(overrides)
+}
+
+impl CheckerResult {
+    // testlib reports the verdict through the exit code, with the message on stderr
+    pub fn from_testlib(meta: &ProcessMeta, stderr: &str) -> Result<CheckerOutcome, CheckerError> {
+        // isolate only writes the exit code when it isn't 0
+        let exit_code = match meta.status {
+            None => TESTLIB_EXIT_OK,
+            Some(ProcessStatus::RuntimeError) => {
+                meta.exit_code.ok_or(CheckerError::CheckerFailed)?
+            }
+            // killed by a signal, timed out, etc.
+            Some(_) => return Err(CheckerError::CheckerFailed),
+        };
+
+        let result = match exit_code {
+            TESTLIB_EXIT_OK => CheckerResult::Accepted,
+            TESTLIB_EXIT_WRONG_ANSWER | TESTLIB_EXIT_PRESENTATION_ERROR => {
+                CheckerResult::WrongAnswer
+            }
+            TESTLIB_EXIT_FAIL => return Err(CheckerError::CheckerFailed),
+            TESTLIB_EXIT_POINTS => {
+                // stderr looks like "points 0.35 some message"
+                let points = stderr
+                    .split_whitespace()
+                    .find_map(|word| word.parse::<f64>().ok())
+                    .ok_or(CheckerError::InvalidChecker)?;
+
+                if !(0.0..=1.0).contains(&points) {
+                    return Err(CheckerError::InvalidChecker);
+                }
+
+                CheckerResult::Points(points)
+            }
+            _ => return Err(CheckerError::InvalidChecker),
+        };
+
+        let message = stderr.trim();
+
+        Ok(CheckerOutcome {
+            result,
+            message: (!message.is_empty()).then(|| message.to_string()),
+        })
+    }
+}
+
 impl TryFrom<&str> for CheckerResult {
     type Error = CheckerError;
 
@@ -134,6 +287,15 @@ impl TryFrom<&str> for CheckerResult {
             return Ok(CheckerResult::Custom(message.to_string()));
         }
 
+        if value.starts_with("points:") {
+            let (_, points) = value.split_once(':').unwrap();
+
+            return match points.trim().parse::<f64>() {
+                Ok(points) if (0.0..=1.0).contains(&points) => Ok(CheckerResult::Points(points)),
+                _ => Err(CheckerError::InvalidChecker),
+            };
+        }
+
         let text_output = value.to_ascii_lowercase();
 
         if text_output == "ac" || text_output == "accepted" {
@@ -147,3 +309,53 @@ impl TryFrom<&str> for CheckerResult {
         Err(CheckerError::InvalidChecker)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isolate::IsolatedProcess;
+
+    // what isolate writes for a program that exited with 0
+    const META_EXITED_OK: &str =
+        "time:0.004\ntime-wall:0.020\nmax-rss:3328\ncsw-voluntary:2\ncsw-forced:0\n";
+
+    #[test]
+    fn testlib_exit_code_zero_is_accepted() {
+        let meta = IsolatedProcess::parse_meta(META_EXITED_OK).unwrap();
+
+        let outcome = CheckerResult::from_testlib(&meta, "ok 3 numbers\n").unwrap();
+
+        assert!(matches!(outcome.result, CheckerResult::Accepted));
+        assert_eq!(outcome.message.as_deref(), Some("ok 3 numbers"));
+    }
+
+    #[test]
+    fn testlib_wrong_answer_keeps_the_message() {
+        let meta = IsolatedProcess::parse_meta(
+            "time:0.004\ntime-wall:0.020\nmax-rss:3328\nexitcode:1\nstatus:RE\nmessage:Exited with error status 1\n",
+        )
+        .unwrap();
+
+        let outcome =
+            CheckerResult::from_testlib(&meta, "wrong answer expected 3, found 4").unwrap();
+
+        assert!(matches!(outcome.result, CheckerResult::WrongAnswer));
+        assert_eq!(
+            outcome.message.as_deref(),
+            Some("wrong answer expected 3, found 4")
+        );
+    }
+
+    #[test]
+    fn testlib_timeout_is_a_checker_failure() {
+        let meta = IsolatedProcess::parse_meta(
+            "time:2.001\ntime-wall:2.010\nmax-rss:3328\nstatus:TO\nmessage:Time limit exceeded\nkilled:1\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            CheckerResult::from_testlib(&meta, ""),
+            Err(CheckerError::CheckerFailed)
+        ));
+    }
+}
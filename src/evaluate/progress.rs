@@ -0,0 +1,90 @@
+use crate::evaluate::{TestcaseResult, Verdict};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ProgressEvent<'a> {
+    #[serde(rename = "compilation_started")]
+    CompilationStarted,
+    #[serde(rename = "compilation_finished")]
+    CompilationFinished { compiler_output: Option<&'a str> },
+    #[serde(rename = "testcase_finished")]
+    TestcaseFinished(&'a TestcaseResult),
+    #[serde(rename = "evaluation_finished")]
+    EvaluationFinished { verdict: &'a Verdict },
+}
+
+#[derive(Serialize)]
+struct ProgressMessage<'a> {
+    evaluation_id: u64,
+    event: &'a ProgressEvent<'a>,
+}
+
+// evaluations run on blocking threads, so events are serialized there
+//  and pushed to redis by a separate task
+pub struct ProgressReporter {
+    evaluation_id: u64,
+    sender: Option<UnboundedSender<String>>,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        evaluation_id: u64,
+        redis: ConnectionManager,
+        progress_queue: Option<String>,
+    ) -> (Self, Option<JoinHandle<()>>) {
+        let Some(progress_queue) = progress_queue else {
+            return (
+                ProgressReporter {
+                    evaluation_id,
+                    sender: None,
+                },
+                None,
+            );
+        };
+
+        let (sender, receiver) = unbounded_channel();
+
+        let handle = tokio::spawn(forward_progress(redis, progress_queue, receiver));
+
+        (
+            ProgressReporter {
+                evaluation_id,
+                sender: Some(sender),
+            },
+            Some(handle),
+        )
+    }
+
+    pub fn report(&self, event: ProgressEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let message = serde_json::to_string(&ProgressMessage {
+            evaluation_id: self.evaluation_id,
+            event: &event,
+        })
+        .expect("progress event to json should have worked");
+
+        // receiver only goes away once the reporter is dropped
+        let _ = sender.send(message);
+    }
+}
+
+async fn forward_progress(
+    mut redis: ConnectionManager,
+    progress_queue: String,
+    mut receiver: UnboundedReceiver<String>,
+) {
+    while let Some(message) = receiver.recv().await {
+        if let Err(err) = redis.rpush::<_, _, ()>(&progress_queue, message).await {
+            warn!("Failed to publish evaluation progress: {err}");
+        }
+    }
+}
@@ -1,26 +1,86 @@
 use crate::environment::Environment;
 use crate::evaluate::compilation::CompilationError;
+use crate::evaluate::progress::{ProgressEvent, ProgressReporter};
 use crate::evaluate::{begin_evaluation, SuccessfulEvaluation, Verdict};
-use crate::messages::handler::MessageResult;
+use crate::messages::handler::{acknowledge_message, requeue_message, MessageResult};
 use crate::messages::{Evaluation, EvaluationMeta};
 use crate::state::AppState;
 use redis::aio::ConnectionManager;
 use redis::AsyncCommands;
 use std::sync::Arc;
 use tokio::runtime::Handle;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-pub async fn wait_for_available_boxes(state: Arc<AppState>) {
+// the most boxes a single evaluation can take, interactive evaluations need two
+const MAX_NEEDED_BOXES: usize = 2;
+
+// waits until at least `needed_boxes` boxes are free, returns early on shutdown
+pub async fn wait_for_available_boxes(state: Arc<AppState>, needed_boxes: usize) {
     loop {
+        if state.is_shutting_down() {
+            break;
+        }
+
+        // created before checking so a release in between isn't missed
+        let notified = state.available_boxes_notify.notified();
+
         let used_box_ids = state.used_box_ids.lock().await;
         let used_box_ids_cnt = used_box_ids.len();
         drop(used_box_ids);
 
-        if Environment::get().max_evaluations as usize - used_box_ids_cnt >= 2 {
+        if Environment::get().max_evaluations as usize - used_box_ids_cnt >= needed_boxes {
+            break;
+        }
+
+        notified.await;
+    }
+}
+
+async fn publish_evaluation_result(
+    redis: &mut ConnectionManager,
+    output_queue: String,
+    result: &SuccessfulEvaluation,
+    raw_message: &str,
+) {
+    let output_json = serde_json::to_string(result).expect("evaluation to json should have worked");
+
+    if let Err(err) = redis.rpush::<_, _, ()>(output_queue, output_json).await {
+        error!("Failed to publish evaluation result: {err}");
+    } else if let Err(err) = acknowledge_message(redis, raw_message).await {
+        error!("Failed to acknowledge evaluation message: {err}");
+    }
+}
+
+fn compilation_failure(
+    evaluation: &Evaluation,
+    verdict: Verdict,
+    compiler_output: String,
+) -> SuccessfulEvaluation {
+    SuccessfulEvaluation {
+        evaluation_id: evaluation.get_evaluation_id(),
+        verdict,
+        testcases: vec![],
+        subtasks: vec![],
+        score: None,
+        max_time: 0,
+        max_memory: 0,
+        max_memory_bytes: 0,
+        compiler_output: Some(compiler_output),
+        error: None,
+    }
+}
+
+// waits until every running evaluation has published its result and released its boxes
+pub async fn wait_for_running_evaluations(state: Arc<AppState>) {
+    loop {
+        // created before checking so a release in between isn't missed
+        let notified = state.available_boxes_notify.notified();
+
+        if state.used_box_ids.lock().await.is_empty() {
             break;
         }
 
-        state.available_boxes_notify.notified().await;
+        notified.await;
     }
 }
 
@@ -29,8 +89,10 @@ pub async fn handle_evaluation(
     redis_connection: &mut ConnectionManager,
     EvaluationMeta {
         output_queue,
+        progress_queue,
         evaluation,
     }: EvaluationMeta,
+    raw_message: String,
 ) -> MessageResult {
     debug!("got evaluation request: {evaluation:#?}");
 
@@ -39,17 +101,60 @@ pub async fn handle_evaluation(
         _ => 1,
     };
 
+    if (Environment::get().max_evaluations as usize) < needed_boxes {
+        error!(
+            "Evaluation {} needs {needed_boxes} boxes, but only {} are available",
+            evaluation.get_evaluation_id(),
+            Environment::get().max_evaluations
+        );
+
+        let result = SuccessfulEvaluation::system_error(
+            evaluation.get_evaluation_id(),
+            format!("Evaluator can't provide {needed_boxes} sandboxes for this evaluation"),
+        );
+
+        publish_evaluation_result(redis_connection, output_queue, &result, &raw_message).await;
+
+        return MessageResult::Continue;
+    }
+
+    wait_for_available_boxes(state.clone(), needed_boxes).await;
+
+    let queue_is_quiet = matches!(evaluation, Evaluation::Batch(_))
+        && Environment::get().max_parallel_testcases > 1
+        && redis_connection
+            .llen::<_, usize>(&Environment::get().redis_queue_key)
+            .await
+            .is_ok_and(|len| len == 0);
+
     let mut used_box_ids = state.used_box_ids.lock().await;
     let used_box_ids_cnt = used_box_ids.len();
     if Environment::get().max_evaluations as usize - used_box_ids_cnt < needed_boxes {
-        // TODO: maybe system error to client
-        error!("not enough boxes, woop woop");
+        // only happens if a shutdown started while waiting
+        drop(used_box_ids);
+
+        warn!(
+            "Shutting down before boxes were available, requeueing evaluation {}",
+            evaluation.get_evaluation_id()
+        );
+
+        if let Err(err) = requeue_message(redis_connection, &raw_message).await {
+            error!("Failed to requeue evaluation message: {err}");
+        }
+
         return MessageResult::Continue;
     }
 
+    // batch testcases can be spread over idle boxes, but only when nothing else is waiting
+    let wanted_boxes = if queue_is_quiet {
+        needed_boxes.max(Environment::get().max_parallel_testcases as usize)
+    } else {
+        needed_boxes
+    };
+
     let available_box_ids = (0..Environment::get().max_evaluations)
         .filter(|id| !used_box_ids.contains(id))
-        .take(needed_boxes)
+        .take(wanted_boxes)
         .collect::<Vec<_>>();
 
     used_box_ids.extend(&available_box_ids);
@@ -58,6 +163,18 @@ pub async fn handle_evaluation(
 
     drop(used_box_ids);
 
+    state
+        .unfinished_messages
+        .lock()
+        .await
+        .insert(available_box_ids[0], raw_message.clone());
+
+    let (progress, progress_handle) = ProgressReporter::new(
+        evaluation.get_evaluation_id(),
+        redis_connection.clone(),
+        progress_queue,
+    );
+
     let mut redis = redis_connection.clone();
     let handle_state = state.clone();
     Handle::current().spawn_blocking(move || {
@@ -66,14 +183,69 @@ pub async fn handle_evaluation(
             &evaluation.get_evaluation_id(),
             &available_box_ids
         );
-        let res = begin_evaluation(&evaluation, &available_box_ids);
+        let res = begin_evaluation(&evaluation, &available_box_ids, &progress);
         info!(
             "Evaluation finished for {}",
             &evaluation.get_evaluation_id()
         );
         debug!("evaluation finished: {res:#?}");
 
+        let result = match res {
+            Ok(result) => result,
+            Err(CompilationError::CompilationProcessError(err)) => {
+                compilation_failure(&evaluation, Verdict::CompilationError(err.clone()), err)
+            }
+            Err(CompilationError::CompilationTimeLimitExceeded(err)) => {
+                compilation_failure(&evaluation, Verdict::CompilationTimeLimitExceeded, err)
+            }
+            // problem configuration or the sandbox, not the submission
+            Err(err) => {
+                error!(
+                    "Evaluation {} couldn't be compiled: {err}",
+                    evaluation.get_evaluation_id()
+                );
+
+                SuccessfulEvaluation::system_error(evaluation.get_evaluation_id(), err.to_string())
+            }
+        };
+
+        progress.report(ProgressEvent::EvaluationFinished {
+            verdict: &result.verdict,
+        });
+
+        // make sure every progress event is out before the result
+        drop(progress);
+
         Handle::current().block_on(async move {
+            if let Some(handle) = progress_handle {
+                let _ = handle.await;
+            }
+
+            // if the message is gone, it was already requeued during shutdown
+            let requeued = !handle_state
+                .unfinished_messages
+                .lock()
+                .await
+                .contains_key(&available_box_ids[0]);
+
+            if requeued {
+                warn!(
+                    "Evaluation {} was requeued, not publishing result",
+                    result.evaluation_id
+                );
+            } else {
+                publish_evaluation_result(&mut redis, output_queue, &result, &raw_message).await;
+
+                // removed only once published, a shutdown in between requeues it again,
+                //  a duplicate result is better than a lost one
+                handle_state
+                    .unfinished_messages
+                    .lock()
+                    .await
+                    .remove(&available_box_ids[0]);
+            }
+
+            // boxes are released only after publishing so shutdown can wait on them
             let mut used_box_ids = handle_state.used_box_ids.lock().await;
             for id in &available_box_ids {
                 used_box_ids.remove(id);
@@ -83,39 +255,12 @@ pub async fn handle_evaluation(
 
             handle_state.available_boxes_notify.notify_waiters();
         });
-
-        let result = match res {
-            Ok(result) => result,
-            Err(err) => {
-                let error = match err {
-                    CompilationError::CompilationProcessError(err) => err,
-                    _ => err.to_string(),
-                };
-
-                SuccessfulEvaluation {
-                    evaluation_id: evaluation.get_evaluation_id(),
-                    verdict: Verdict::CompilationError(error.clone()),
-                    testcases: vec![],
-                    max_time: 0,
-                    max_memory: 0,
-                    compiler_output: Some(error),
-                }
-            }
-        };
-
-        let output_json =
-            serde_json::to_string(&result).expect("evaluation to json should have worked");
-
-        let publish_result = Handle::current()
-            .block_on(async move { redis.rpush::<_, _, ()>(output_queue, output_json).await });
-
-        if let Err(err) = publish_result {
-            error!("Failed to publish evaluation result: {err}");
-        }
     });
 
-    if Environment::get().max_evaluations as usize - used_box_ids_cnt <= 1 {
-        wait_for_available_boxes(state.clone()).await;
+    // don't pull the next message before it could be started right away
+    let next_needed_boxes = MAX_NEEDED_BOXES.min(Environment::get().max_evaluations as usize);
+    if Environment::get().max_evaluations as usize - used_box_ids_cnt < next_needed_boxes {
+        wait_for_available_boxes(state.clone(), next_needed_boxes).await;
         return MessageResult::Continue;
     }
 
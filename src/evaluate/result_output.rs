@@ -0,0 +1,75 @@
+use crate::environment::Environment;
+use crate::evaluate::Verdict;
+use crate::messages::{OutputEncoding, ResultOptions};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+// turns program output and stderr into result fields, capped so they can't blow up the result
+pub struct ResultOutputFormatter<'a> {
+    options: &'a ResultOptions,
+    max_output_bytes: usize,
+    max_error_bytes: usize,
+}
+
+impl<'a> ResultOutputFormatter<'a> {
+    pub fn new(options: &'a ResultOptions) -> Self {
+        ResultOutputFormatter {
+            options,
+            max_output_bytes: options
+                .max_output_bytes
+                .unwrap_or(Environment::get().result_output_max_bytes),
+            max_error_bytes: options
+                .max_error_bytes
+                .unwrap_or(Environment::get().result_error_max_bytes),
+        }
+    }
+
+    // formatted content and the number of bytes cut off by the cap
+    pub fn output(&self, verdict: &Verdict, stdout: &[u8]) -> (Option<String>, usize) {
+        if self.options.omit_accepted_output && *verdict == Verdict::Accepted {
+            return (None, 0);
+        }
+
+        let (output, truncated_bytes) = self.format(stdout, self.max_output_bytes);
+
+        (Some(output), truncated_bytes)
+    }
+
+    pub fn error(&self, stderr: &[u8]) -> (String, usize) {
+        self.format(stderr, self.max_error_bytes)
+    }
+
+    fn format(&self, content: &[u8], max_bytes: usize) -> (String, usize) {
+        let mut kept = &content[..content.len().min(max_bytes)];
+
+        match self.options.encoding {
+            OutputEncoding::Text => {
+                // a character cut in half by the cap is dropped instead of being replaced
+                match std::str::from_utf8(kept) {
+                    Err(err) if kept.len() < content.len() && err.error_len().is_none() => {
+                        kept = &kept[..err.valid_up_to()];
+                    }
+                    _ => {}
+                }
+
+                let truncated_bytes = content.len() - kept.len();
+
+                // NUL isn't allowed in most text columns
+                let mut text = String::from_utf8_lossy(kept).replace('\0', "\u{FFFD}");
+                text.push_str(&truncation_marker(truncated_bytes));
+
+                (text, truncated_bytes)
+            }
+            // only the program's bytes, truncation is reported separately
+            OutputEncoding::Base64 => (STANDARD.encode(kept), content.len() - kept.len()),
+        }
+    }
+}
+
+fn truncation_marker(omitted_bytes: usize) -> String {
+    if omitted_bytes == 0 {
+        return String::new();
+    }
+
+    format!("\n[truncated, {omitted_bytes} more bytes]")
+}
@@ -1,6 +1,7 @@
+use crate::evaluate::language::{CommandDefinition, LanguageDefinition};
 use crate::isolate::meta::ProcessMeta;
-use crate::isolate::{CommandMeta, IsolateError, IsolateLimits, IsolatedProcess, ProcessInput};
-use crate::util;
+use crate::isolate::{IsolateError, IsolateLimits, IsolatedProcess, ProcessInput};
+use crate::messages::SourceFile;
 use std::os::fd::OwnedFd;
 use std::path::PathBuf;
 use std::process::Output;
@@ -14,24 +15,49 @@ pub enum ProcessRunError {
 
 #[derive(Debug)]
 pub struct CompiledProcessData {
-    pub executable_path: PathBuf,
+    pub artifact_path: PathBuf,
+    // name of the artifact inside the box
+    pub artifact_name: String,
+    // other submission files needed at run time
+    pub files: Vec<SourceFile>,
+    pub run: CommandDefinition,
 }
 
 #[derive(Debug)]
-pub struct PythonProcessData {
+pub struct InterpretedProcessData {
     pub code: String,
+    pub source_file: String,
+    pub files: Vec<SourceFile>,
+    pub run: CommandDefinition,
 }
 
 #[derive(Debug)]
-pub struct JavaProcessData {
-    pub built_class_name: PathBuf,
+pub enum RunnableProcess {
+    Compiled(CompiledProcessData),
+    Interpreted(InterpretedProcessData),
 }
 
+// a file written into the box before the process starts
 #[derive(Debug)]
-pub enum RunnableProcess {
-    Compiled(CompiledProcessData),
-    Python(PythonProcessData),
-    Java(JavaProcessData),
+pub struct BoxFile<'a> {
+    pub path: &'a str,
+    pub content: &'a [u8],
+}
+
+impl<'a> From<&'a SourceFile> for BoxFile<'a> {
+    fn from(value: &'a SourceFile) -> Self {
+        BoxFile {
+            path: &value.path,
+            content: value.content.as_bytes(),
+        }
+    }
+}
+
+// extra arguments and files for a run, used for e.g. testlib checkers
+#[derive(Debug, Default)]
+pub struct RunExtras<'a> {
+    pub args: Vec<String>,
+    pub files: Vec<BoxFile<'a>>,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +67,31 @@ pub struct ProcessRunResult {
 }
 
 impl RunnableProcess {
+    // wraps a compiled artifact into a process that runs it
+    pub fn from_artifact(
+        path: PathBuf,
+        artifact_name: &str,
+        run: &CommandDefinition,
+        files: Vec<SourceFile>,
+    ) -> Self {
+        RunnableProcess::Compiled(CompiledProcessData {
+            artifact_path: path,
+            artifact_name: artifact_name.to_string(),
+            files,
+            run: run.clone(),
+        })
+    }
+
+    // without a compile step, every file is needed at run time
+    pub fn from_source(definition: &LanguageDefinition, code: &str, files: &[SourceFile]) -> Self {
+        RunnableProcess::Interpreted(InterpretedProcessData {
+            code: code.to_string(),
+            source_file: definition.source_file.clone(),
+            files: files.to_vec(),
+            run: definition.run.clone(),
+        })
+    }
+
     pub fn run(
         &self,
         exec_id: u8,
@@ -48,7 +99,18 @@ impl RunnableProcess {
         limits: &IsolateLimits,
         output_pipe: Option<OwnedFd>,
     ) -> Result<ProcessRunResult, ProcessRunError> {
-        let mut process = self.just_run(exec_id, input, limits, output_pipe)?;
+        self.run_with_extras(exec_id, input, limits, output_pipe, &RunExtras::default())
+    }
+
+    pub fn run_with_extras(
+        &self,
+        exec_id: u8,
+        input: ProcessInput,
+        limits: &IsolateLimits,
+        output_pipe: Option<OwnedFd>,
+        extras: &RunExtras,
+    ) -> Result<ProcessRunResult, ProcessRunError> {
+        let mut process = self.just_run_with_extras(exec_id, input, limits, output_pipe, extras)?;
 
         let output = process.wait_for_output()?;
 
@@ -66,19 +128,51 @@ impl RunnableProcess {
         limits: &IsolateLimits,
         output_pipe: Option<OwnedFd>,
     ) -> Result<IsolatedProcess, ProcessRunError> {
-        let mut process = self.as_isolated(exec_id, limits)?;
-
-        match self {
-            RunnableProcess::Compiled(CompiledProcessData { executable_path }) => process
-                .spawn_with_hooks(input, output_pipe, |isolated| {
-                    isolated.copy_in_box(executable_path, "program")
-                })?,
-            RunnableProcess::Python(_) => process.spawn(input, output_pipe)?,
-            RunnableProcess::Java(JavaProcessData { built_class_name }) => process
-                .spawn_with_hooks(input, output_pipe, |isolated| {
-                    isolated.copy_in_box(built_class_name, "Main.class")
-                })?,
-        };
+        self.just_run_with_extras(exec_id, input, limits, output_pipe, &RunExtras::default())
+    }
+
+    pub fn just_run_with_extras(
+        &self,
+        exec_id: u8,
+        input: ProcessInput,
+        limits: &IsolateLimits,
+        output_pipe: Option<OwnedFd>,
+        extras: &RunExtras,
+    ) -> Result<IsolatedProcess, ProcessRunError> {
+        let mut process = self.as_isolated(exec_id, limits, &extras.args)?;
+
+        process.spawn_with_hooks(input, output_pipe, |isolated| {
+            let files = match self {
+                RunnableProcess::Compiled(CompiledProcessData {
+                    artifact_path,
+                    artifact_name,
+                    files,
+                    ..
+                }) => {
+                    isolated.copy_in_box(artifact_path, artifact_name)?;
+                    files
+                }
+                RunnableProcess::Interpreted(InterpretedProcessData {
+                    code,
+                    source_file,
+                    files,
+                    ..
+                }) => {
+                    isolated.write_in_box(source_file, code.as_bytes())?;
+                    files
+                }
+            };
+
+            for file in files {
+                isolated.write_in_box(&file.path, file.content.as_bytes())?;
+            }
+
+            for file in &extras.files {
+                isolated.write_in_box(file.path, file.content)?;
+            }
+
+            Ok(())
+        })?;
 
         Ok(process)
     }
@@ -87,43 +181,16 @@ impl RunnableProcess {
         &self,
         exec_id: u8,
         limits: &IsolateLimits,
+        extra_args: &[String],
     ) -> Result<IsolatedProcess, IsolateError> {
-        let process = match self {
-            RunnableProcess::Compiled(_) => IsolatedProcess::new(
-                exec_id,
-                &CommandMeta {
-                    executable: "program".to_string(),
-                    args: Vec::new(),
-                    in_path: false,
-                    system: false,
-                },
-                limits,
-                vec![],
-            )?,
-            RunnableProcess::Python(PythonProcessData { code }) => IsolatedProcess::new(
-                exec_id,
-                &CommandMeta {
-                    executable: "/usr/bin/python3".to_string(),
-                    args: vec!["-c".to_string(), code.clone()],
-                    in_path: true,
-                    system: false,
-                },
-                limits,
-                vec![],
-            )?,
-            RunnableProcess::Java(_) => IsolatedProcess::new(
-                exec_id,
-                &CommandMeta {
-                    executable: "/usr/bin/java".to_string(),
-                    args: vec!["Main".to_string()],
-                    in_path: true,
-                    system: false,
-                },
-                limits,
-                util::general::ETC_JAVA_DIRECTORIES.clone(),
-            )?,
+        let run = match self {
+            RunnableProcess::Compiled(CompiledProcessData { run, .. }) => run,
+            RunnableProcess::Interpreted(InterpretedProcessData { run, .. }) => run,
         };
 
-        Ok(process)
+        let mut command_meta = run.command_meta();
+        command_meta.args.extend_from_slice(extra_args);
+
+        IsolatedProcess::new(exec_id, &command_meta, limits, run.dir_mounts.clone())
     }
 }
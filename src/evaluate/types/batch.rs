@@ -1,10 +1,17 @@
-use crate::evaluate::compilation::{process_compilation, CompilationError};
-use crate::evaluate::output::{CheckerResult, OutputChecker};
+use crate::environment::Environment;
+use crate::evaluate::compilation::{process_compilation, CompilationError, CompilationTarget};
+use crate::evaluate::output::{CheckerOutcome, OutputChecker};
+use crate::evaluate::progress::{ProgressEvent, ProgressReporter};
+use crate::evaluate::result_output::ResultOutputFormatter;
 use crate::evaluate::runnable::{ProcessRunResult, RunnableProcess};
-use crate::evaluate::{SuccessfulEvaluation, TestcaseResult, Verdict};
-use crate::isolate::meta::ProcessStatus;
+use crate::evaluate::{
+    average_score, failure_verdict, SubtaskResult, SuccessfulEvaluation, TestcaseResult, Verdict,
+};
 use crate::isolate::{IsolateLimits, ProcessInput};
-use crate::messages::{BatchEvaluation, Testcase};
+use crate::messages::{BatchEvaluation, EvaluationMode, Subtask, SubtaskScoring, Testcase};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 fn evaluate_with_testcase(
     box_id: u8,
@@ -12,6 +19,7 @@ fn evaluate_with_testcase(
     checker: &OutputChecker,
     testcase: &Testcase,
     limits: &IsolateLimits,
+    formatter: &ResultOutputFormatter,
 ) -> TestcaseResult {
     let running_process = process.run(
         box_id,
@@ -23,123 +31,393 @@ fn evaluate_with_testcase(
     let ProcessRunResult { output, meta } = match running_process {
         Ok(it) => it,
         Err(err) => {
-            return TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: Verdict::SystemError,
-                memory: 0,
-                time: 0,
-                output: None,
-                error: Some(err.to_string()),
-            }
+            return TestcaseResult::without_run(
+                &testcase.id,
+                Verdict::SystemError,
+                Some(err.to_string()),
+            )
         }
     };
 
     let output_str = String::from_utf8_lossy(&output.stdout).to_string();
 
-    // FIXME: repeated
-    if !output.status.success() {
-        let verdict = if let Some(ProcessStatus::TimedOut) = meta.status {
-            Verdict::TimeLimitExceeded
-        } else if meta.cg_oom_killed {
-            Verdict::MemoryLimitExceeded
-        } else {
-            Verdict::RuntimeError
-        };
-
-        return TestcaseResult {
-            id: testcase.id.clone(),
-            verdict,
-            memory: meta.cg_mem_kb,
-            time: meta.time_ms,
-            output: None,
-            error: Some(String::from_utf8_lossy(&output.stderr).to_string()),
-        };
+    // the program can exit on its own before it's killed for its output
+    if !output.status.success() || meta.output_limit_exceeded {
+        return TestcaseResult::from_meta(&testcase.id, failure_verdict(&meta, limits), &meta)
+            .with_error(formatter, &output.stderr);
     }
 
-    let check_result = match checker.check(box_id, &output_str, testcase) {
+    let CheckerOutcome { result, message } = match checker.check(box_id, &output_str, testcase) {
         Ok(result) => result,
         Err(err) => {
-            return TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: (&err).into(),
-                memory: 0,
-                time: 0,
-                output: None,
-                error: Some(err.to_string()),
-            }
+            return TestcaseResult::without_run(&testcase.id, (&err).into(), Some(err.to_string()))
         }
     };
 
-    let verdict = match check_result {
-        CheckerResult::Accepted => Verdict::Accepted,
-        CheckerResult::WrongAnswer => Verdict::WrongAnswer,
-        CheckerResult::Custom(message) => Verdict::Custom(message),
+    TestcaseResult::from_meta(&testcase.id, Verdict::from(result), &meta)
+        .with_checker_message(message)
+        .with_output(formatter, &output.stdout)
+}
+
+// first failing verdict, then the first partially scored one, or the last judged one
+fn global_verdict(testcase_results: &[TestcaseResult]) -> Verdict {
+    let mut judged = testcase_results
+        .iter()
+        .filter(|it| it.verdict != Verdict::Skipped);
+
+    judged
+        .clone()
+        .find(|it| !it.verdict.is_passing())
+        .or_else(|| {
+            judged
+                .clone()
+                .find(|it| matches!(it.verdict, Verdict::Partial(_)))
+        })
+        .or_else(|| judged.next_back())
+        .map(|it| it.verdict.clone())
+        .unwrap_or(Verdict::Accepted)
+}
+
+fn score_subtask(
+    subtask: &Subtask,
+    testcase_results: &[TestcaseResult],
+    dependencies_passed: bool,
+) -> SubtaskResult {
+    if !dependencies_passed {
+        return SubtaskResult {
+            id: subtask.id,
+            verdict: Verdict::Skipped,
+            score: 0.0,
+        };
+    }
+
+    // most likely a typo in the testcase subtask ids, points can't be given for nothing
+    if testcase_results.is_empty() {
+        return SubtaskResult {
+            id: subtask.id,
+            verdict: Verdict::JudgingError,
+            score: 0.0,
+        };
+    }
+
+    let scores = testcase_results.iter().map(|it| it.verdict.score());
+
+    let fraction = match subtask.scoring {
+        SubtaskScoring::Min => scores.fold(1.0, f64::min),
+        SubtaskScoring::Sum => scores.sum::<f64>() / testcase_results.len() as f64,
     };
 
-    TestcaseResult {
-        id: testcase.id.clone(),
-        verdict,
-        memory: meta.cg_mem_kb,
-        time: meta.time_ms,
-        output: Some(output_str),
-        error: None,
+    SubtaskResult {
+        id: subtask.id,
+        verdict: global_verdict(testcase_results),
+        score: subtask.score * fraction,
+    }
+}
+
+struct JudgingState {
+    results: Vec<Option<TestcaseResult>>,
+    // results are reported in testcase order as soon as all previous ones are done,
+    //  up to and including the first failure
+    reported: usize,
+    stopped_reporting: bool,
+}
+
+// judges testcases on all given boxes at once, the results (including skipping after
+//  the first failure) are the same as when judging them one by one in order
+fn judge_on_boxes<F>(
+    testcases: &[&Testcase],
+    box_ids: &[u8],
+    stop_on_failure: bool,
+    progress: &ProgressReporter,
+    judge: F,
+) -> Vec<TestcaseResult>
+where
+    F: Fn(u8, &Testcase) -> TestcaseResult + Sync,
+{
+    let next_position = AtomicUsize::new(0);
+    let first_failure = AtomicUsize::new(usize::MAX);
+    let state = Mutex::new(JudgingState {
+        results: testcases.iter().map(|_| None).collect(),
+        reported: 0,
+        stopped_reporting: false,
+    });
+
+    let should_skip =
+        |position: usize| stop_on_failure && position > first_failure.load(Ordering::SeqCst);
+
+    thread::scope(|scope| {
+        for &box_id in box_ids.iter().take(testcases.len()) {
+            let (next_position, first_failure, state, judge) =
+                (&next_position, &first_failure, &state, &judge);
+
+            scope.spawn(move || loop {
+                let position = next_position.fetch_add(1, Ordering::SeqCst);
+
+                if position >= testcases.len() {
+                    break;
+                }
+
+                if should_skip(position) {
+                    continue;
+                }
+
+                let result = judge(box_id, testcases[position]);
+
+                if !result.verdict.is_passing() {
+                    first_failure.fetch_min(position, Ordering::SeqCst);
+                }
+
+                let mut state = state.lock().expect("judging state lock");
+                state.results[position] = Some(result);
+
+                while !state.stopped_reporting {
+                    let Some(Some(result)) = state.results.get(state.reported) else {
+                        break;
+                    };
+
+                    progress.report(ProgressEvent::TestcaseFinished(result));
+
+                    let failed = !result.verdict.is_passing();
+                    state.stopped_reporting = stop_on_failure && failed;
+                    state.reported += 1;
+                }
+            });
+        }
+    });
+
+    let state = state.into_inner().expect("judging state lock");
+
+    state
+        .results
+        .into_iter()
+        .enumerate()
+        .map(|(position, result)| match result {
+            Some(result) if position < state.reported => result,
+            Some(result) if !should_skip(position) => {
+                progress.report(ProgressEvent::TestcaseFinished(&result));
+                result
+            }
+            _ => {
+                let result = TestcaseResult::skipped(&testcases[position].id);
+                progress.report(ProgressEvent::TestcaseFinished(&result));
+                result
+            }
+        })
+        .collect()
+}
+
+// dependencies have to point to subtasks defined before the dependent one, and with
+//  subtasks every testcase has to belong to one of them
+fn validate_subtasks(subtasks: &[Subtask], testcases: &[Testcase]) -> Result<(), String> {
+    for (position, subtask) in subtasks.iter().enumerate() {
+        let defined_before = &subtasks[..position];
+
+        if let Some(dependency) = subtask
+            .dependencies
+            .iter()
+            .find(|dependency| !defined_before.iter().any(|it| it.id == **dependency))
+        {
+            return Err(format!(
+                "Subtask {} depends on subtask {dependency}, which isn't defined before it",
+                subtask.id
+            ));
+        }
+    }
+
+    if subtasks.is_empty() {
+        return Ok(());
+    }
+
+    let stray_testcase = testcases.iter().find(|testcase| {
+        !subtasks
+            .iter()
+            .any(|subtask| testcase.subtask == Some(subtask.id))
+    });
+
+    match stray_testcase {
+        Some(Testcase {
+            id,
+            subtask: Some(subtask),
+            ..
+        }) => Err(format!(
+            "Testcase {id} belongs to subtask {subtask}, which isn't defined"
+        )),
+        Some(Testcase { id, .. }) => Err(format!("Testcase {id} doesn't belong to any subtask")),
+        None => Ok(()),
     }
 }
 
 pub fn evaluate(
     evaluation: &BatchEvaluation,
-    box_id: u8,
+    box_ids: &[u8],
+    progress: &ProgressReporter,
 ) -> Result<SuccessfulEvaluation, CompilationError> {
-    let compilation_result = process_compilation(&evaluation.code, &evaluation.language, box_id)?;
+    if let Err(error) = validate_subtasks(&evaluation.subtasks, &evaluation.testcases) {
+        return Ok(SuccessfulEvaluation::system_error(evaluation.id, error));
+    }
+
+    // compilation and the checker only need one box, testcases can use all of them
+    let box_id = box_ids[0];
+
+    progress.report(ProgressEvent::CompilationStarted);
+
+    // graders are given per language, the submission is linked with the one for its language
+    let grader = if evaluation.graders.is_empty() {
+        None
+    } else {
+        let grader = evaluation
+            .graders
+            .iter()
+            .find(|it| it.language == evaluation.language)
+            .ok_or_else(|| CompilationError::MissingGrader(evaluation.language.clone()))?;
+
+        Some(grader.files.as_slice())
+    };
+
+    let compilation_result = process_compilation(
+        &evaluation.code,
+        &evaluation.files,
+        grader,
+        &evaluation.language,
+        &evaluation.compile_limits,
+        box_id,
+        CompilationTarget::Submission,
+    )?;
+
+    progress.report(ProgressEvent::CompilationFinished {
+        compiler_output: compilation_result.compiler_stderr.as_deref(),
+    });
 
     let checker = OutputChecker::try_from((box_id, &evaluation.checker))?;
 
     let limits = IsolateLimits {
-        time_limit: evaluation.time_limit as f32 / 1000.0,
-        memory_limit: evaluation.memory_limit,
+        output_limit: Some(
+            evaluation
+                .output_limit
+                .unwrap_or(Environment::get().output_limit_kb),
+        ),
+        ..IsolateLimits::new(
+            evaluation.time_limit as f32 / 1000.0,
+            evaluation.memory_limit,
+        )
     };
 
-    let mut global_verdict = Verdict::Accepted;
+    let formatter = ResultOutputFormatter::new(&evaluation.result_options);
 
-    let mut testcase_results = Vec::<TestcaseResult>::new();
+    let stop_on_failure = evaluation.evaluation_mode == EvaluationMode::StopOnFirstFailure;
 
-    for testcase in &evaluation.testcases {
-        if global_verdict != Verdict::Accepted && !matches!(global_verdict, Verdict::Custom(_)) {
-            testcase_results.push(TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: Verdict::Skipped,
-                memory: 0,
-                time: 0,
-                output: None,
-                error: None,
-            });
-            continue;
+    let judge_testcases = |indices: &[usize], skip_all: bool, stop_on_failure: bool| {
+        let testcases = indices
+            .iter()
+            .map(|&index| &evaluation.testcases[index])
+            .collect::<Vec<_>>();
+
+        if skip_all {
+            return testcases
+                .into_iter()
+                .map(|testcase| {
+                    let result = TestcaseResult::skipped(&testcase.id);
+                    progress.report(ProgressEvent::TestcaseFinished(&result));
+                    result
+                })
+                .collect();
         }
 
-        let result = evaluate_with_testcase(
-            box_id,
-            &compilation_result.process,
-            &checker,
-            testcase,
-            &limits,
+        judge_on_boxes(
+            &testcases,
+            box_ids,
+            stop_on_failure,
+            progress,
+            |box_id, testcase| {
+                evaluate_with_testcase(
+                    box_id,
+                    &compilation_result.process,
+                    &checker,
+                    testcase,
+                    &limits,
+                    &formatter,
+                )
+            },
+        )
+    };
+
+    let mut testcase_results = evaluation
+        .testcases
+        .iter()
+        .map(|_| None)
+        .collect::<Vec<Option<TestcaseResult>>>();
+
+    let mut subtask_results = Vec::<SubtaskResult>::new();
+
+    for subtask in &evaluation.subtasks {
+        let dependencies_passed = subtask.dependencies.iter().all(|dependency| {
+            subtask_results
+                .iter()
+                .find(|it| it.id == *dependency)
+                .is_some_and(|it| it.verdict.is_passing())
+        });
+
+        let indices = evaluation
+            .testcases
+            .iter()
+            .enumerate()
+            .filter(|(_, testcase)| testcase.subtask == Some(subtask.id))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        let results = judge_testcases(
+            &indices,
+            !dependencies_passed,
+            stop_on_failure && subtask.scoring == SubtaskScoring::Min,
         );
-        let result_verdict = result.verdict.clone();
 
-        testcase_results.push(result);
+        subtask_results.push(score_subtask(subtask, &results, dependencies_passed));
 
-        global_verdict = result_verdict;
+        for (index, result) in indices.into_iter().zip(results) {
+            testcase_results[index] = Some(result);
+        }
     }
 
+    // without subtasks, all testcases are judged together
+    let remaining_indices = testcase_results
+        .iter()
+        .enumerate()
+        .filter(|(_, result)| result.is_none())
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    let results = judge_testcases(&remaining_indices, false, stop_on_failure);
+
+    for (index, result) in remaining_indices.into_iter().zip(results) {
+        testcase_results[index] = Some(result);
+    }
+
+    let testcase_results = testcase_results.into_iter().flatten().collect::<Vec<_>>();
+
+    let score = if evaluation.subtasks.is_empty() {
+        average_score(&testcase_results)
+    } else {
+        Some(subtask_results.iter().map(|it| it.score).sum())
+    };
+
     Ok(SuccessfulEvaluation {
         evaluation_id: evaluation.id,
-        verdict: global_verdict,
+        verdict: global_verdict(&testcase_results),
         max_memory: testcase_results
             .iter()
             .map(|it| it.memory)
             .max()
             .unwrap_or(0),
+        max_memory_bytes: testcase_results
+            .iter()
+            .map(|it| it.memory_bytes)
+            .max()
+            .unwrap_or(0),
         max_time: testcase_results.iter().map(|it| it.time).max().unwrap_or(0),
         testcases: testcase_results,
+        subtasks: subtask_results,
+        score,
         compiler_output: compilation_result.compiler_stderr,
+        error: None,
     })
 }
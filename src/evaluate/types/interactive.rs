@@ -1,10 +1,19 @@
-use crate::evaluate::compilation::{process_compilation, CompilationError};
-use crate::evaluate::output::CheckerResult;
-use crate::evaluate::runnable::{ProcessRunError, RunnableProcess};
-use crate::evaluate::{SuccessfulEvaluation, TestcaseResult, Verdict};
-use crate::isolate::meta::ProcessStatus;
+use crate::environment::Environment;
+use crate::evaluate::compilation::{process_compilation, CompilationError, CompilationTarget};
+use crate::evaluate::output::{
+    checker_limits, CheckerOutcome, CheckerResult, TESTLIB_ANSWER_FILE, TESTLIB_INPUT_FILE,
+    TESTLIB_OUTPUT_FILE,
+};
+use crate::evaluate::progress::{ProgressEvent, ProgressReporter};
+use crate::evaluate::result_output::ResultOutputFormatter;
+use crate::evaluate::runnable::{BoxFile, ProcessRunError, RunExtras, RunnableProcess};
+use crate::evaluate::{
+    average_score, failure_verdict, SuccessfulEvaluation, TestcaseResult, Verdict,
+};
 use crate::isolate::{IsolateError, IsolateLimits, ProcessInput};
-use crate::messages::{InteractiveEvaluation, Testcase};
+use crate::messages::{
+    CheckerProtocol, EvaluationMode, InteractiveEvaluation, LimitOverrides, Testcase,
+};
 use crate::util::fd::{write_to_fd_safe, LargeWriteStrategy, SafeFdWriteError};
 use crate::util::general::random_bytes;
 use std::fs;
@@ -36,28 +45,59 @@ enum InteractError {
     FdWriteError(#[from] SafeFdWriteError),
 }
 
+#[allow(clippy::too_many_arguments)]
 fn interact_with_testcase(
     process: &RunnableProcess,
     interactor: &RunnableProcess,
     testcase: &Testcase,
     limits: &IsolateLimits,
+    interactor_limits: &IsolateLimits,
     box_id: u8,
     interactor_box_id: u8,
+    protocol: CheckerProtocol,
+    formatter: &ResultOutputFormatter,
 ) -> Result<TestcaseResult, InteractError> {
     let (interactor_input, process_output) = nix::unistd::pipe()?;
     let (process_input, interactor_output) = nix::unistd::pipe()?;
 
-    let write_handle = write_to_fd_safe(
-        process_output.as_fd(),
-        &[testcase.input.as_bytes(), b"\n"].concat(),
-        LargeWriteStrategy::Async,
-    )?;
+    // legacy interactors read the testcase input from stdin, before the program output,
+    //  testlib ones get it as a file
+    let (write_handle, extras) = match protocol {
+        CheckerProtocol::Legacy => (
+            Some(write_to_fd_safe(
+                process_output.as_fd(),
+                &[testcase.input.as_bytes(), b"\n"].concat(),
+                LargeWriteStrategy::Async,
+            )?),
+            RunExtras::default(),
+        ),
+        CheckerProtocol::Testlib => (
+            None,
+            RunExtras {
+                args: [TESTLIB_INPUT_FILE, TESTLIB_OUTPUT_FILE, TESTLIB_ANSWER_FILE]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                files: vec![
+                    BoxFile {
+                        path: TESTLIB_INPUT_FILE,
+                        content: testcase.input.as_bytes(),
+                    },
+                    BoxFile {
+                        path: TESTLIB_ANSWER_FILE,
+                        content: testcase.output.as_bytes(),
+                    },
+                ],
+            },
+        ),
+    };
 
-    let mut interactor = interactor.just_run(
+    let mut interactor = interactor.just_run_with_extras(
         interactor_box_id,
         ProcessInput::Piped(interactor_input),
-        limits,
+        interactor_limits,
         Some(interactor_output),
+        &extras,
     )?;
 
     let mut process = process.just_run(
@@ -68,96 +108,108 @@ fn interact_with_testcase(
     )?;
 
     let process_output = process.wait_for_output()?;
-    let _ = interactor.wait_for_output()?;
+    let interactor_output = interactor.wait_for_output()?;
 
     drop(write_handle);
 
     let process_meta = process.load_meta()?;
 
     // TODO: may not work, stdout is connected to interactor
-    let process_stdout = String::from_utf8_lossy(&process_output.stdout).to_string();
+    let process_stdout = &process_output.stdout;
 
-    // FIXME: repeated
-    if !process_output.status.success() {
+    if !process_output.status.success() || process_meta.output_limit_exceeded {
         process.cleanup_and_reset()?;
         interactor.cleanup_and_reset()?;
 
-        let verdict = if let Some(ProcessStatus::TimedOut) = process_meta.status {
-            Verdict::TimeLimitExceeded
-        } else if process_meta.cg_oom_killed {
-            Verdict::MemoryLimitExceeded
-        } else {
-            Verdict::RuntimeError
-        };
+        let verdict = failure_verdict(&process_meta, limits);
 
-        return Ok(TestcaseResult {
-            id: testcase.id.clone(),
-            verdict,
-            memory: process_meta.cg_mem_kb,
-            time: process_meta.time_ms,
-            output: Some(process_stdout),
-            error: Some(String::from_utf8_lossy(&process_output.stderr).to_string()),
-        });
+        return Ok(
+            TestcaseResult::from_meta(&testcase.id, verdict, &process_meta)
+                .with_output(formatter, process_stdout)
+                .with_error(formatter, &process_output.stderr),
+        );
     }
 
-    let out_meta_file = PathBuf::from(format!("/tmp/{}", random_bytes(8)));
-    interactor.move_out_of_box("interactor_meta.out", &out_meta_file)?;
+    let check_result = match protocol {
+        CheckerProtocol::Legacy => {
+            let out_meta_file = PathBuf::from(format!("/tmp/{}", random_bytes(8)));
+            interactor.move_out_of_box("interactor_meta.out", &out_meta_file)?;
 
-    process.cleanup_and_reset()?;
-    interactor.cleanup_and_reset()?;
+            process.cleanup_and_reset()?;
+            interactor.cleanup_and_reset()?;
 
-    let mut interactor_meta_file = File::open(&out_meta_file)?;
+            let mut interactor_meta_file = File::open(&out_meta_file)?;
 
-    let mut interactor_result = String::new();
+            let mut interactor_result = String::new();
 
-    interactor_meta_file.read_to_string(&mut interactor_result)?;
+            interactor_meta_file.read_to_string(&mut interactor_result)?;
 
-    fs::remove_file(&out_meta_file)?;
+            fs::remove_file(&out_meta_file)?;
 
-    let check_result = CheckerResult::try_from(interactor_result.trim());
+            CheckerResult::try_from(interactor_result.trim()).map(CheckerOutcome::from)
+        }
+        CheckerProtocol::Testlib => {
+            let interactor_meta = interactor.load_meta()?;
 
-    let check_result = match check_result {
-        Ok(result) => result,
-        Err(err) => {
-            return Ok(TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: Verdict::from(&err),
-                memory: 0,
-                time: 0,
-                output: Some(process_stdout),
-                error: Some(err.to_string()),
-            })
+            process.cleanup_and_reset()?;
+            interactor.cleanup_and_reset()?;
+
+            CheckerResult::from_testlib(
+                &interactor_meta,
+                &String::from_utf8_lossy(&interactor_output.stderr),
+            )
         }
     };
 
-    let verdict = match check_result {
-        CheckerResult::Accepted => Verdict::Accepted,
-        CheckerResult::WrongAnswer => Verdict::WrongAnswer,
-        CheckerResult::Custom(message) => Verdict::Custom(message),
+    let CheckerOutcome { result, message } = match check_result {
+        Ok(result) => result,
+        Err(err) => {
+            return Ok(TestcaseResult::without_run(
+                &testcase.id,
+                (&err).into(),
+                Some(err.to_string()),
+            )
+            .with_output(formatter, process_stdout));
+        }
     };
 
-    Ok(TestcaseResult {
-        id: testcase.id.clone(),
-        verdict,
-        // TODO: backend most likely wants bytes
-        memory: process_meta.cg_mem_kb,
-        time: process_meta.time_ms,
-        output: Some(process_stdout),
-        error: None,
-    })
+    Ok(
+        TestcaseResult::from_meta(&testcase.id, Verdict::from(result), &process_meta)
+            .with_checker_message(message)
+            .with_output(formatter, process_stdout),
+    )
 }
 
 pub fn evaluate(
     evaluation: &InteractiveEvaluation,
     box_id: u8,
     interactor_box_id: u8,
+    progress: &ProgressReporter,
 ) -> Result<SuccessfulEvaluation, CompilationError> {
-    let compiled_program = process_compilation(&evaluation.code, &evaluation.language, box_id)?;
+    progress.report(ProgressEvent::CompilationStarted);
+
+    let compiled_program = process_compilation(
+        &evaluation.code,
+        &evaluation.files,
+        None,
+        &evaluation.language,
+        &evaluation.compile_limits,
+        box_id,
+        CompilationTarget::Submission,
+    )?;
+
+    progress.report(ProgressEvent::CompilationFinished {
+        compiler_output: compiled_program.compiler_stderr.as_deref(),
+    });
 
     let compiled_interactor = process_compilation(
         &evaluation.checker.script,
+        &[],
+        None,
         &evaluation.checker.language,
+        &LimitOverrides::default(),
         interactor_box_id,
+        CompilationTarget::Checker,
     )?;
 
     let program = compiled_program.process;
@@ -165,24 +217,35 @@ pub fn evaluate(
     let interactor = compiled_interactor.process;
 
     let limits = IsolateLimits {
-        time_limit: evaluation.time_limit as f32 / 1000.0,
-        memory_limit: evaluation.memory_limit,
+        output_limit: Some(
+            evaluation
+                .output_limit
+                .unwrap_or(Environment::get().output_limit_kb),
+        ),
+        ..IsolateLimits::new(
+            evaluation.time_limit as f32 / 1000.0,
+            evaluation.memory_limit,
+        )
     };
 
     let mut global_verdict = Verdict::Accepted;
 
     let mut testcase_results = Vec::<TestcaseResult>::new();
 
+    let interactor_limits =
+        checker_limits(&evaluation.checker.language, &evaluation.checker.limits);
+
+    let formatter = ResultOutputFormatter::new(&evaluation.result_options);
+
+    let stop_on_failure = evaluation.evaluation_mode == EvaluationMode::StopOnFirstFailure;
+
     for testcase in &evaluation.testcases {
-        if global_verdict != Verdict::Accepted && !matches!(global_verdict, Verdict::Custom(_)) {
-            testcase_results.push(TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: Verdict::Skipped,
-                memory: 0,
-                time: 0,
-                output: None,
-                error: None,
-            });
+        if stop_on_failure && !global_verdict.is_passing() {
+            let result = TestcaseResult::skipped(&testcase.id);
+
+            progress.report(ProgressEvent::TestcaseFinished(&result));
+
+            testcase_results.push(result);
             continue;
         }
 
@@ -191,27 +254,32 @@ pub fn evaluate(
             &interactor,
             testcase,
             &limits,
+            &interactor_limits,
             box_id,
             interactor_box_id,
+            evaluation.checker.protocol,
+            &formatter,
         );
 
         let result = match result {
             Ok(res) => res,
-            Err(err) => TestcaseResult {
-                id: testcase.id.clone(),
-                verdict: Verdict::SystemError,
-                time: 0,
-                memory: 0,
-                output: None,
-                error: Some(err.to_string()),
-            },
+            Err(err) => TestcaseResult::without_run(
+                &testcase.id,
+                Verdict::SystemError,
+                Some(err.to_string()),
+            ),
         };
 
         let result_verdict = result.verdict.clone();
 
+        progress.report(ProgressEvent::TestcaseFinished(&result));
+
         testcase_results.push(result);
 
-        global_verdict = result_verdict;
+        // keep the verdict of the first failure
+        if global_verdict.is_passing() {
+            global_verdict = result_verdict;
+        }
     }
 
     Ok(SuccessfulEvaluation {
@@ -222,8 +290,16 @@ pub fn evaluate(
             .map(|it| it.memory)
             .max()
             .unwrap_or(0),
+        max_memory_bytes: testcase_results
+            .iter()
+            .map(|it| it.memory_bytes)
+            .max()
+            .unwrap_or(0),
         max_time: testcase_results.iter().map(|it| it.time).max().unwrap_or(0),
+        subtasks: vec![],
+        score: average_score(&testcase_results),
         testcases: testcase_results,
         compiler_output: compiled_program.compiler_stderr,
+        error: None,
     })
 }
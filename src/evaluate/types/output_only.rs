@@ -1,5 +1,6 @@
 use crate::evaluate::compilation::CompilationError;
-use crate::evaluate::output::{CheckerResult, OutputChecker};
+use crate::evaluate::output::{CheckerOutcome, OutputChecker};
+use crate::evaluate::progress::{ProgressEvent, ProgressReporter};
 use crate::evaluate::{SuccessfulEvaluation, TestcaseResult, Verdict};
 use crate::messages::{OutputOnlyEvaluation, Testcase};
 
@@ -9,47 +10,35 @@ fn evaluate_with_testcase(
     testcase: &Testcase,
     box_id: u8,
 ) -> TestcaseResult {
-    let Ok(check_result) = checker.check(box_id, output, testcase) else {
-        return TestcaseResult {
-            id: testcase.id.clone(),
-            verdict: Verdict::JudgingError,
-            memory: 0,
-            time: 0,
-            output: None,
-            error: None,
-        };
+    let Ok(CheckerOutcome { result, message }) = checker.check(box_id, output, testcase) else {
+        return TestcaseResult::without_run(&testcase.id, Verdict::JudgingError, None);
     };
 
-    let verdict = match check_result {
-        CheckerResult::Accepted => Verdict::Accepted,
-        CheckerResult::WrongAnswer => Verdict::WrongAnswer,
-        CheckerResult::Custom(message) => Verdict::Custom(message),
-    };
-
-    TestcaseResult {
-        id: testcase.id.clone(),
-        verdict,
-        memory: 0,
-        time: 0,
-        output: None,
-        error: None,
-    }
+    TestcaseResult::without_run(&testcase.id, Verdict::from(result), None)
+        .with_checker_message(message)
 }
 
 pub fn evaluate(
     evaluation: &OutputOnlyEvaluation,
     box_id: u8,
+    progress: &ProgressReporter,
 ) -> Result<SuccessfulEvaluation, CompilationError> {
     let checker = OutputChecker::try_from((box_id, &evaluation.checker))?;
 
     let result = evaluate_with_testcase(&evaluation.output, &checker, &evaluation.testcase, box_id);
 
+    progress.report(ProgressEvent::TestcaseFinished(&result));
+
     Ok(SuccessfulEvaluation {
         evaluation_id: evaluation.id,
         verdict: result.verdict.clone(),
         max_memory: 0,
+        max_memory_bytes: 0,
         max_time: 0,
+        subtasks: vec![],
+        score: Some(result.verdict.score()),
         testcases: vec![result],
         compiler_output: None,
+        error: None,
     })
 }
@@ -1,3 +1,5 @@
+use nix::sys::signal::Signal;
+
 #[derive(Debug, Clone)]
 pub enum ProcessStatus {
     RuntimeError,
@@ -6,12 +8,58 @@ pub enum ProcessStatus {
     SandboxError,
 }
 
+// https://www.ucw.cz/moe/isolate.1.html#_meta_files
 #[derive(Debug, Clone)]
 pub struct ProcessMeta {
-    pub cg_mem_kb: u32,
+    // cg-mem, or max-rss without cgroups
+    pub memory_kb: u32,
     pub status: Option<ProcessStatus>,
+    // cpu time
     pub time_ms: u32,
+    pub wall_time_ms: u32,
     pub cg_oom_killed: bool,
+    pub exit_code: Option<i32>,
+    pub exit_signal: Option<i32>,
+    // killed by isolate, e.g. after a timeout, the status already says why so it's only
+    //  there for debugging
+    #[allow(dead_code)]
+    pub killed: bool,
+    // human-readable status from isolate
+    pub message: Option<String>,
+    pub max_rss_kb: Option<u32>,
+    // not used for verdicts either, only for debugging
+    #[allow(dead_code)]
+    pub voluntary_context_switches: u32,
+    #[allow(dead_code)]
+    pub forced_context_switches: u32,
+    // not from isolate, set when the captured output was cut off
+    pub output_limit_exceeded: bool,
+}
+
+impl ProcessMeta {
+    pub fn memory_bytes(&self) -> u64 {
+        self.memory_kb as u64 * 1024
+    }
+
+    // isolate reports both kinds of timeouts as TO, the wall clock one is only in the message
+    pub fn timed_out_on_wall_clock(&self) -> bool {
+        matches!(self.status, Some(ProcessStatus::TimedOut))
+            && self
+                .message
+                .as_ref()
+                .is_some_and(|message| message.contains("wall clock"))
+    }
+
+    // e.g. "SIGSEGV", or the number if it's not a known signal
+    pub fn exit_signal_name(&self) -> Option<String> {
+        let signal = self.exit_signal?;
+
+        Some(
+            Signal::try_from(signal)
+                .map(|it| it.as_str().to_string())
+                .unwrap_or_else(|_| signal.to_string()),
+        )
+    }
 }
 
 impl TryFrom<&String> for ProcessStatus {
@@ -2,14 +2,22 @@ pub mod meta;
 
 use crate::environment::Environment;
 use crate::isolate::meta::{ProcessMeta, ProcessStatus};
+use crate::messages::LimitOverrides;
 use crate::util;
+use crate::util::affinity;
 use crate::util::fd::{LargeWriteStrategy, SafeFdWriteError, WriteHandle};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{File, Permissions};
+use std::io::{self, Read, Write};
 use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 const ISOLATE_BINARY_LOCATION: &str = "/usr/local/bin/isolate";
 
@@ -70,6 +78,29 @@ pub struct CommandMeta {
 pub struct IsolateLimits {
     pub time_limit: f32,
     pub memory_limit: u32,
+    pub process_limit: u32,
+    // KiB, caps every file written in the box and the captured stdout and stderr
+    pub output_limit: Option<u32>,
+}
+
+impl IsolateLimits {
+    pub fn new(time_limit: f32, memory_limit: u32) -> Self {
+        IsolateLimits {
+            time_limit,
+            memory_limit,
+            process_limit: PID_LIMIT,
+            output_limit: None,
+        }
+    }
+
+    pub fn with_overrides(self, overrides: &LimitOverrides) -> Self {
+        IsolateLimits {
+            time_limit: overrides.time_limit.unwrap_or(self.time_limit),
+            memory_limit: overrides.memory_limit.unwrap_or(self.memory_limit),
+            process_limit: overrides.process_limit.unwrap_or(self.process_limit),
+            output_limit: overrides.output_limit.or(self.output_limit),
+        }
+    }
 }
 
 pub enum ProcessInput {
@@ -94,6 +125,10 @@ pub struct IsolatedProcess {
     running_child: Option<IsolateRunningChild>,
 
     write_handle: Option<WriteHandle>,
+
+    // bytes
+    output_limit: Option<usize>,
+    output_limit_exceeded: bool,
 }
 
 impl IsolatedProcess {
@@ -111,16 +146,23 @@ impl IsolatedProcess {
         isolate_command.arg("-E");
         isolate_command.arg("PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
 
-        isolate_command.arg(format!("--processes={PID_LIMIT}"));
+        isolate_command.arg(format!("--processes={}", limits.process_limit));
+
+        if let Some(output_limit) = limits.output_limit {
+            isolate_command.arg(format!("--fsize={output_limit}"));
+        }
 
         if Environment::get().run_with_cgroups {
             isolate_command.arg("--cg");
         }
 
         isolate_command.arg("--wall-time");
+        // the cap doesn't apply to configured limits above it, e.g. slow compilers
         isolate_command.arg(format!(
             "{}",
-            MAX_WALL_TIME_LIMIT_SECONDS.min(limits.time_limit * 2.0)
+            MAX_WALL_TIME_LIMIT_SECONDS
+                .max(limits.time_limit)
+                .min(limits.time_limit * 2.0)
         ));
 
         isolate_command.arg("--time");
@@ -146,6 +188,14 @@ impl IsolatedProcess {
 
         isolate_command.stderr(Stdio::piped());
 
+        // affinity is inherited by the sandboxed program
+        if let Some(core) = affinity::core_for_box(execution_id) {
+            // SAFETY: the hook only makes a single syscall
+            unsafe {
+                isolate_command.pre_exec(move || affinity::pin_current_process(core));
+            }
+        }
+
         Ok(IsolatedProcess {
             box_id: execution_id,
             command_meta: command_meta.clone(),
@@ -153,6 +203,8 @@ impl IsolatedProcess {
             dir_mounts,
             write_handle: None,
             running_child: None,
+            output_limit: limits.output_limit.map(|it| it as usize * 1024),
+            output_limit_exceeded: false,
         })
     }
 
@@ -241,14 +293,6 @@ impl IsolatedProcess {
         Ok(())
     }
 
-    pub fn spawn(
-        &mut self,
-        input: ProcessInput,
-        output_fd: Option<OwnedFd>,
-    ) -> Result<(), IsolateError> {
-        self.spawn_with_hooks(input, output_fd, |_| Ok(()))
-    }
-
     fn spawn_init(&mut self) -> Result<PathBuf, IsolateError> {
         let mut isolate_command = Command::new(ISOLATE_BINARY_LOCATION);
 
@@ -301,23 +345,7 @@ impl IsolatedProcess {
     }
 
     pub fn cleanup_and_reset(&mut self) -> Result<(), IsolateError> {
-        let mut isolate_command = Command::new(ISOLATE_BINARY_LOCATION);
-
-        isolate_command.arg("--box-id");
-        isolate_command.arg(format!("{}", self.box_id));
-
-        if Environment::get().run_with_cgroups {
-            isolate_command.arg("--cg");
-        }
-
-        isolate_command.arg("--cleanup");
-
-        isolate_command.stdout(Stdio::piped());
-        isolate_command.stderr(Stdio::piped());
-
-        let child = isolate_command.spawn()?;
-
-        child.wait_with_output()?;
+        cleanup_box(self.box_id)?;
 
         std::fs::remove_file(format!("/tmp/.meta-{}", self.box_id))?;
 
@@ -349,6 +377,28 @@ impl IsolatedProcess {
         Ok(())
     }
 
+    pub fn write_in_box(&mut self, path: &str, content: &[u8]) -> Result<(), IsolateError> {
+        let Some(running) = &self.running_child else {
+            return Err(IsolateError::ProcessNotRunning);
+        };
+
+        let path_in_box = running.work_dir.join(path);
+
+        // nested directories have to be writable by the sandboxed user, e.g. for object files
+        if let Some(parent) = Path::new(path).parent() {
+            for directory in parent.ancestors().filter(|it| !it.as_os_str().is_empty()) {
+                let directory = running.work_dir.join(directory);
+
+                std::fs::create_dir_all(&directory)?;
+                std::fs::set_permissions(&directory, Permissions::from_mode(0o777))?;
+            }
+        }
+
+        std::fs::write(path_in_box, content)?;
+
+        Ok(())
+    }
+
     pub fn load_meta(&self) -> Result<ProcessMeta, IsolateError> {
         let Some(child) = &self.running_child else {
             return Err(IsolateError::ProcessNotRunning);
@@ -362,7 +412,8 @@ impl IsolatedProcess {
 
         let meta_file_content = std::fs::read_to_string(format!("/tmp/.meta-{}", self.box_id))?;
 
-        let meta = Self::parse_meta(&meta_file_content)?;
+        let mut meta = Self::parse_meta(&meta_file_content)?;
+        meta.output_limit_exceeded = self.output_limit_exceeded;
 
         Ok(meta)
     }
@@ -370,28 +421,47 @@ impl IsolatedProcess {
     pub fn parse_meta(meta_content: &str) -> Result<ProcessMeta, IsolateError> {
         let key_value: HashMap<String, String> = meta_content
             .lines()
-            .map(|line| line.split(':').map(String::from).collect::<Vec<String>>())
-            .filter(|kv| kv.len() == 2)
-            .map(|kv| (kv[0].clone(), kv[1].clone()))
+            // the message can contain colons too
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
             .collect();
 
+        let seconds_as_ms = |key: &str| {
+            key_value
+                .get(key)
+                .and_then(|val| val.parse::<f64>().ok())
+                .map(|val| (val * 1000.0) as u32)
+                .unwrap_or(0)
+        };
+
+        let number = |key: &str| key_value.get(key).and_then(|val| val.parse::<u32>().ok());
+
+        let max_rss_kb = number("max-rss");
+
         let meta = ProcessMeta {
-            cg_mem_kb: key_value
-                .get("cg-mem")
-                .and_then(|val| val.parse::<u32>().ok())
-                .unwrap_or(0),
+            // cg-mem is only there with cgroups
+            memory_kb: number("cg-mem").or(max_rss_kb).unwrap_or(0),
             status: key_value
                 .get("status")
                 .and_then(|it| TryInto::<ProcessStatus>::try_into(it).ok()),
-            time_ms: key_value
-                .get("time")
-                .and_then(|val| val.parse::<f64>().ok())
-                .map(|val| (val * 1000.0) as u32)
-                .unwrap_or(0),
+            time_ms: seconds_as_ms("time"),
+            wall_time_ms: seconds_as_ms("time-wall"),
             cg_oom_killed: key_value
                 .get("cg-oom-killed")
                 .map(|val| val == "1")
                 .unwrap_or(false),
+            exit_code: key_value
+                .get("exitcode")
+                .and_then(|val| val.parse::<i32>().ok()),
+            exit_signal: key_value
+                .get("exitsig")
+                .and_then(|val| val.parse::<i32>().ok()),
+            killed: key_value.get("killed").is_some_and(|val| val == "1"),
+            message: key_value.get("message").cloned(),
+            max_rss_kb,
+            voluntary_context_switches: number("csw-voluntary").unwrap_or(0),
+            forced_context_switches: number("csw-forced").unwrap_or(0),
+            output_limit_exceeded: false,
         };
 
         Ok(meta)
@@ -403,9 +473,36 @@ impl IsolatedProcess {
             .take()
             .ok_or(IsolateError::ProcessNotRunning)?;
 
-        let child_process = child.child.take().ok_or(IsolateError::ProcessNotRunning)?;
+        let mut child_process = child.child.take().ok_or(IsolateError::ProcessNotRunning)?;
+
+        let stdout_pipe = child_process.stdout.take();
+        let stderr_pipe = child_process.stderr.take();
+
+        let isolate_pid = Pid::from_raw(child_process.id() as i32);
+        let limit_exceeded = AtomicBool::new(false);
+
+        // isolate kills the box when interrupted, the other pipe closes once it's gone
+        let on_limit_exceeded = || {
+            if !limit_exceeded.swap(true, Ordering::SeqCst) {
+                let _ = signal::kill(isolate_pid, Signal::SIGTERM);
+            }
+        };
+
+        let (stdout, stderr) = thread::scope(|scope| {
+            let stderr =
+                scope.spawn(|| read_with_limit(stderr_pipe, self.output_limit, &on_limit_exceeded));
+            let stdout = read_with_limit(stdout_pipe, self.output_limit, &on_limit_exceeded);
 
-        let output = child_process.wait_with_output()?;
+            (stdout, stderr.join().expect("stderr reader panicked"))
+        });
+
+        let output = std::process::Output {
+            status: child_process.wait()?,
+            stdout: stdout?,
+            stderr: stderr?,
+        };
+
+        self.output_limit_exceeded = limit_exceeded.into_inner();
 
         if let Some(handle) = self.write_handle.take() {
             drop(handle);
@@ -445,6 +542,58 @@ impl IsolatedProcess {
     }
 }
 
+// reads the whole pipe, or up to `limit` bytes, after which the pipe is closed
+// also used on boxes whose process handle is gone, e.g. ones still running on shutdown
+pub fn cleanup_box(box_id: u8) -> Result<(), IsolateError> {
+    let mut isolate_command = Command::new(ISOLATE_BINARY_LOCATION);
+
+    isolate_command.arg("--box-id");
+    isolate_command.arg(format!("{}", box_id));
+
+    if Environment::get().run_with_cgroups {
+        isolate_command.arg("--cg");
+    }
+
+    isolate_command.arg("--cleanup");
+
+    isolate_command.stdout(Stdio::piped());
+    isolate_command.stderr(Stdio::piped());
+
+    let child = isolate_command.spawn()?;
+
+    child.wait_with_output()?;
+
+    Ok(())
+}
+
+fn read_with_limit(
+    pipe: Option<impl Read>,
+    limit: Option<usize>,
+    on_limit_exceeded: &impl Fn(),
+) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+
+    let Some(mut pipe) = pipe else {
+        return Ok(content);
+    };
+
+    let Some(limit) = limit else {
+        pipe.read_to_end(&mut content)?;
+
+        return Ok(content);
+    };
+
+    // one byte over the limit to tell if it was exceeded
+    pipe.take(limit as u64 + 1).read_to_end(&mut content)?;
+
+    if content.len() > limit {
+        content.truncate(limit);
+        on_limit_exceeded();
+    }
+
+    Ok(content)
+}
+
 impl Drop for IsolatedProcess {
     fn drop(&mut self) {
         if self.running_child.is_some() {
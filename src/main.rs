@@ -1,10 +1,11 @@
 use crate::environment::Environment;
+use crate::evaluate::language::LanguageRegistry;
+use crate::evaluate::queue_handler::wait_for_running_evaluations;
 use crate::state::AppState;
 use crate::tracing::setup_tracing;
-use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
-use ::tracing::{error, info};
+use std::time::Duration;
+use ::tracing::{error, info, warn};
 
 mod messages;
 mod state;
@@ -23,6 +24,13 @@ fn main() -> anyhow::Result<()> {
 
     setup_tracing();
 
+    if let Err(err) = LanguageRegistry::init() {
+        error!("Error loading language registry: {err:#}");
+        return Err(anyhow::anyhow!("Error loading language registry"));
+    }
+
+    util::affinity::log_core_assignment();
+
     let rt = tokio::runtime::Builder::new_current_thread()
         .worker_threads(1)
         .enable_all()
@@ -36,33 +44,62 @@ fn main() -> anyhow::Result<()> {
 }
 
 async fn start() -> anyhow::Result<()> {
-    // TODO: doesn't wait for current evaluation to finish
-
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
 
+    let state = Arc::new(AppState::default());
+
+    let client = redis::Client::open(&*Environment::get().redis_url)?;
+
+    let handler = entrypoint(state.clone(), client.clone());
+    tokio::pin!(handler);
+
     tokio::select! {
-        _ = entrypoint() => {},
+        result = &mut handler => result?,
         _ = tokio::signal::ctrl_c() => {
             info!("Shutting down");
+            state.begin_shutdown();
+            handler.await?;
         },
         _ = sigterm.recv() => {
             info!("Shutting down");
+            state.begin_shutdown();
+            handler.await?;
+        }
+    }
+
+    let shutdown_timeout = Duration::from_secs(Environment::get().shutdown_timeout_seconds);
+
+    info!(
+        "Waiting up to {}s for running evaluations to finish",
+        shutdown_timeout.as_secs()
+    );
+
+    let finished = tokio::time::timeout(
+        shutdown_timeout,
+        wait_for_running_evaluations(state.clone()),
+    )
+    .await;
+
+    if finished.is_err() {
+        warn!("Running evaluations didn't finish in time, requeueing them");
+
+        messages::handler::requeue_unfinished_messages(state.clone(), client).await?;
+
+        // best effort, whatever survives is stopped by its own limits and
+        //  the box is initialized again when it's next used
+        for box_id in state.used_box_ids.lock().await.iter() {
+            if let Err(err) = isolate::cleanup_box(*box_id) {
+                warn!("Failed to clean up box {box_id}: {err}");
+            }
         }
     }
 
     Ok(())
 }
 
-async fn entrypoint() -> anyhow::Result<()> {
+async fn entrypoint(state: Arc<AppState>, client: redis::Client) -> anyhow::Result<()> {
     info!("Starting...");
 
-    let state = Arc::new(AppState {
-        used_box_ids: Mutex::from(HashSet::new()),
-        available_boxes_notify: Notify::new(),
-    });
-
-    let client = redis::Client::open(&*Environment::get().redis_url)?;
-
     info!("Started");
 
     info!(
@@ -70,7 +107,7 @@ async fn entrypoint() -> anyhow::Result<()> {
         Environment::get().max_evaluations
     );
 
-    messages::handler::handle_messages(state, client).await;
+    messages::handler::handle_messages(state, client).await?;
 
     Ok(())
 }
@@ -1,11 +1,20 @@
 use crate::environment::Environment;
 use crate::evaluate::queue_handler::handle_evaluation;
+use crate::evaluate::SuccessfulEvaluation;
 use crate::messages::{Message, SystemMessage};
 use crate::state::AppState;
 use redis::aio::ConnectionManager;
-use redis::{AsyncCommands, Client};
+use redis::{AsyncCommands, Client, Direction};
 use std::sync::Arc;
-use tracing::{info, warn};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// how long a single pull blocks before checking for shutdown again
+const QUEUE_POLL_TIMEOUT_SECONDS: f64 = 1.0;
+
+// a worker whose heartbeat key expired is considered dead and its processing list is requeued
+const HEARTBEAT_INTERVAL_SECONDS: u64 = 5;
+const HEARTBEAT_TTL_SECONDS: u64 = 3 * HEARTBEAT_INTERVAL_SECONDS;
 
 #[derive(Debug, thiserror::Error)]
 pub enum MessageHandlerError {
@@ -18,18 +27,47 @@ pub enum MessageResult {
     Exit,
 }
 
+pub struct QueueMessage {
+    // raw payload as it was in the queue, needed to acknowledge or requeue the message
+    pub payload: String,
+    pub message: Message,
+}
+
+fn processing_queue_key(worker_id: &str) -> String {
+    format!(
+        "{}:processing:{}",
+        Environment::get().redis_queue_key,
+        worker_id
+    )
+}
+
+fn heartbeat_key(worker_id: &str) -> String {
+    format!(
+        "{}:heartbeat:{}",
+        Environment::get().redis_queue_key,
+        worker_id
+    )
+}
+
+fn workers_key() -> String {
+    format!("{}:workers", Environment::get().redis_queue_key)
+}
+
 async fn handle_single_message(
     state: Arc<AppState>,
-    message: Message,
+    QueueMessage { payload, message }: QueueMessage,
     connection: &mut ConnectionManager,
 ) -> MessageResult {
     match message {
         Message::System(SystemMessage::Exit) => MessageResult::Exit,
-        Message::BeginEvaluation(meta) => handle_evaluation(state, connection, meta).await,
+        Message::BeginEvaluation(meta) => handle_evaluation(state, connection, *meta, payload).await,
     }
 }
 
-pub async fn handle_messages(state: Arc<AppState>, redis_client: Client) {
+pub async fn handle_messages(
+    state: Arc<AppState>,
+    redis_client: Client,
+) -> Result<(), MessageHandlerError> {
     let mut msg_connection = redis_client
         .get_connection_manager()
         .await
@@ -40,7 +78,26 @@ pub async fn handle_messages(state: Arc<AppState>, redis_client: Client) {
         .await
         .expect("Redis connection manager");
 
+    if Environment::get().reliable_queue {
+        // without it nothing would requeue our messages after a crash, so don't start at all
+        if let Err(err) = register_worker(&mut msg_connection).await {
+            error!("Failed to register worker: {err}");
+            return Err(err);
+        }
+
+        if let Err(err) = requeue_orphaned_messages(&mut msg_connection).await {
+            warn!("Failed to requeue orphaned messages: {err}");
+        }
+
+        tokio::spawn(keep_heartbeat(msg_connection.clone()));
+    }
+
     'outer: loop {
+        if state.is_shutting_down() {
+            info!("Shutdown requested, no longer pulling messages");
+            break 'outer;
+        }
+
         let msg = pull_redis_message(&mut msg_connection).await;
 
         let message = match msg {
@@ -63,33 +120,282 @@ pub async fn handle_messages(state: Arc<AppState>, redis_client: Client) {
             }
         }
     }
+
+    Ok(())
 }
 
 async fn pull_redis_message(
     connection: &mut ConnectionManager,
-) -> Result<Option<Message>, MessageHandlerError> {
+) -> Result<Option<QueueMessage>, MessageHandlerError> {
     if Environment::get().exit_on_empty_queue {
         let in_queue: usize = connection.llen(&Environment::get().redis_queue_key).await?;
 
         if in_queue == 0 {
             info!("Work queue empty, broadcasting exit");
-            return Ok(Some(Message::System(SystemMessage::Exit)));
+            return Ok(Some(QueueMessage {
+                payload: String::new(),
+                message: Message::System(SystemMessage::Exit),
+            }));
         }
     }
 
-    let val: Option<(String, String)> = connection
-        .blpop(&Environment::get().redis_queue_key, 0.0)
-        .await?;
+    let val: Option<String> = if Environment::get().reliable_queue {
+        connection
+            .blmove(
+                &Environment::get().redis_queue_key,
+                processing_queue_key(&Environment::get().worker_id),
+                Direction::Left,
+                Direction::Right,
+                QUEUE_POLL_TIMEOUT_SECONDS,
+            )
+            .await?
+    } else {
+        connection
+            .blpop::<_, Option<(String, String)>>(
+                &Environment::get().redis_queue_key,
+                QUEUE_POLL_TIMEOUT_SECONDS,
+            )
+            .await?
+            .map(|(_, val)| val)
+    };
 
-    let Some((_, val)) = val else {
+    let Some(val) = val else {
         return Ok(None);
     };
 
     let message = serde_json::from_str::<Message>(&val);
 
-    let Ok(msg) = message else {
-        return Ok(None);
+    let msg = match message {
+        Ok(msg) => msg,
+        Err(err) => {
+            handle_malformed_message(connection, &val, &err).await?;
+
+            return Ok(None);
+        }
     };
 
-    Ok(Some(msg))
+    // only evaluations produce a result, everything else is done as soon as it's pulled
+    if !matches!(msg, Message::BeginEvaluation(_)) {
+        acknowledge_message(connection, &val).await?;
+    }
+
+    Ok(Some(QueueMessage {
+        payload: val,
+        message: msg,
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetter<'a> {
+    payload: &'a str,
+    error: String,
+    worker_id: &'a str,
+}
+
+// recovers just enough of an evaluation message to be able to reply to it
+fn recover_evaluation_target(payload: &str) -> Option<(String, u64)> {
+    let value = serde_json::from_str::<serde_json::Value>(payload).ok()?;
+    let meta = value.get("BeginEvaluation")?;
+
+    let output_queue = meta.get("output_queue")?.as_str()?.to_string();
+    let evaluation_id = meta
+        .get("evaluation")?
+        .as_object()?
+        .values()
+        .next()?
+        .get("id")?
+        .as_u64()?;
+
+    Some((output_queue, evaluation_id))
+}
+
+async fn handle_malformed_message(
+    connection: &mut ConnectionManager,
+    payload: &str,
+    error: &serde_json::Error,
+) -> Result<(), MessageHandlerError> {
+    warn!("Received malformed message, moving to dead letter queue: {error}");
+
+    let dead_letter = serde_json::to_string(&DeadLetter {
+        payload,
+        error: error.to_string(),
+        worker_id: &Environment::get().worker_id,
+    })
+    .expect("dead letter to json should have worked");
+
+    connection
+        .rpush::<_, _, ()>(&Environment::get().redis_dead_letter_key, dead_letter)
+        .await?;
+
+    if let Some((output_queue, evaluation_id)) = recover_evaluation_target(payload) {
+        let result = SuccessfulEvaluation::system_error(
+            evaluation_id,
+            format!("Malformed evaluation message: {error}"),
+        );
+
+        let output_json =
+            serde_json::to_string(&result).expect("evaluation to json should have worked");
+
+        connection
+            .rpush::<_, _, ()>(output_queue, output_json)
+            .await?;
+    }
+
+    acknowledge_message(connection, payload).await
+}
+
+// removes a message from this worker's processing list once it's fully handled,
+//  no-op when not running with a reliable queue
+pub async fn acknowledge_message(
+    connection: &mut ConnectionManager,
+    payload: &str,
+) -> Result<(), MessageHandlerError> {
+    if !Environment::get().reliable_queue {
+        return Ok(());
+    }
+
+    connection
+        .lrem::<_, _, ()>(
+            processing_queue_key(&Environment::get().worker_id),
+            1,
+            payload,
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn register_worker(connection: &mut ConnectionManager) -> Result<(), MessageHandlerError> {
+    let worker_id = &Environment::get().worker_id;
+
+    connection
+        .set_ex::<_, _, ()>(heartbeat_key(worker_id), 1, HEARTBEAT_TTL_SECONDS)
+        .await?;
+    connection
+        .sadd::<_, _, ()>(workers_key(), worker_id)
+        .await?;
+
+    info!("Registered as reliable queue worker {worker_id}");
+
+    Ok(())
+}
+
+async fn keep_heartbeat(mut connection: ConnectionManager) {
+    let mut interval = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let result = connection
+            .set_ex::<_, _, ()>(
+                heartbeat_key(&Environment::get().worker_id),
+                1,
+                HEARTBEAT_TTL_SECONDS,
+            )
+            .await;
+
+        if let Err(err) = result {
+            warn!("Failed to refresh heartbeat: {err}");
+        }
+    }
+}
+
+// moves entries left in processing lists of dead workers (and our own, from a previous run)
+//  back to the front of the work queue
+async fn requeue_orphaned_messages(
+    connection: &mut ConnectionManager,
+) -> Result<(), MessageHandlerError> {
+    let own_worker_id = &Environment::get().worker_id;
+    let workers: Vec<String> = connection.smembers(workers_key()).await?;
+
+    for worker_id in workers {
+        if &worker_id != own_worker_id {
+            let alive: bool = connection.exists(heartbeat_key(&worker_id)).await?;
+
+            if alive {
+                continue;
+            }
+        }
+
+        let mut requeued = 0;
+
+        loop {
+            // taking from the back and pushing to the front keeps the original order
+            let moved: Option<String> = connection
+                .lmove(
+                    processing_queue_key(&worker_id),
+                    &Environment::get().redis_queue_key,
+                    Direction::Right,
+                    Direction::Left,
+                )
+                .await?;
+
+            if moved.is_none() {
+                break;
+            }
+
+            requeued += 1;
+        }
+
+        if requeued > 0 {
+            info!("Requeued {requeued} orphaned messages from worker {worker_id}");
+        }
+
+        if &worker_id != own_worker_id {
+            connection
+                .srem::<_, _, ()>(workers_key(), &worker_id)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn requeue_unfinished_messages(
+    state: Arc<AppState>,
+    redis_client: Client,
+) -> Result<(), MessageHandlerError> {
+    let mut connection = redis_client.get_connection_manager().await?;
+
+    let messages = state
+        .unfinished_messages
+        .lock()
+        .await
+        .drain()
+        .map(|(_, message)| message)
+        .collect::<Vec<_>>();
+
+    info!("Requeueing {} unfinished evaluations", messages.len());
+
+    for message in messages {
+        requeue_message(&mut connection, &message).await?;
+    }
+
+    Ok(())
+}
+
+// puts a pulled message back to the front of the work queue,
+//  so it's picked up before newer messages
+pub async fn requeue_message(
+    connection: &mut ConnectionManager,
+    payload: &str,
+) -> Result<(), MessageHandlerError> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    if Environment::get().reliable_queue {
+        pipe.lrem(
+            processing_queue_key(&Environment::get().worker_id),
+            1,
+            payload,
+        )
+        .ignore();
+    }
+
+    pipe.lpush(&Environment::get().redis_queue_key, payload)
+        .ignore();
+
+    pipe.query_async::<()>(connection).await?;
+
+    Ok(())
 }
@@ -2,52 +2,194 @@ use std::fmt::{Display, Formatter};
 
 pub mod handler;
 
-#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum EvaluationLanguage {
+    // C11, "c" stays the name for backward compatibility
+    #[serde(alias = "c11")]
     C,
+    C17,
+    // C++17, "cpp" stays the name for backward compatibility
+    #[serde(alias = "cpp17")]
     Cpp,
+    Cpp20,
+    Cpp23,
     Python,
+    #[serde(rename = "pypy3")]
+    PyPy3,
     Rust,
     Java,
     Go,
     GnuAsmX86Linux,
     #[serde(rename = "ocaml")]
     OCaml,
+    Kotlin,
+    #[serde(rename = "csharp")]
+    CSharp,
+    #[serde(rename = "javascript")]
+    JavaScript,
+    Haskell,
+    // any other name, only usable if it's defined in the language registry file
+    #[serde(untagged)]
+    Custom(String),
 }
 
 impl Display for EvaluationLanguage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            EvaluationLanguage::Custom(name) => write!(f, "{name}"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckerProtocol {
+    // input, answer and output separated on stdin, verdict printed to stdout
+    #[default]
+    Legacy,
+    // testlib.h style, files passed as arguments and verdict reported via exit code
+    Testlib,
+}
+
+// compilation and checker limits, unset ones are taken from the language and then the defaults
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct LimitOverrides {
+    // seconds
+    pub time_limit: Option<f32>,
+    // KiB
+    pub memory_limit: Option<u32>,
+    pub process_limit: Option<u32>,
+    // KiB, size of any file written in the box
+    pub output_limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputEncoding {
+    // invalid UTF-8 is replaced
+    #[default]
+    Text,
+    // exact bytes
+    Base64,
+}
+
+// how program output and stderr end up in testcase results
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct ResultOptions {
+    // bytes, the evaluator's defaults are used if missing
+    pub max_output_bytes: Option<usize>,
+    pub max_error_bytes: Option<usize>,
+    #[serde(default)]
+    pub omit_accepted_output: bool,
+    #[serde(default)]
+    pub encoding: OutputEncoding,
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct CheckerData {
     pub script: String,
     pub language: EvaluationLanguage,
+    #[serde(default)]
+    pub protocol: CheckerProtocol,
+    // limits for running the checker
+    #[serde(default)]
+    pub limits: LimitOverrides,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvaluationMode {
+    #[default]
+    StopOnFirstFailure,
+    // judges every testcase, the verdict is still the one of the first failure
+    RunAll,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtaskScoring {
+    // full score only if every testcase passes
+    #[default]
+    Min,
+    // score proportional to the number of passed testcases
+    Sum,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Subtask {
+    pub id: u32,
+    pub score: f64,
+    // ids of subtasks that need to pass for this one to be judged,
+    //  they have to be defined before this subtask
+    #[serde(default)]
+    pub dependencies: Vec<u32>,
+    #[serde(default)]
+    pub scoring: SubtaskScoring,
+}
+
+// an additional submission file, next to the main one in `code`
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct SourceFile {
+    // relative to the box, e.g. "include/graph.h"
+    pub path: String,
+    pub content: String,
+}
+
+// official grader linked with the submission, for function-implementation problems
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Grader {
+    pub language: EvaluationLanguage,
+    // grader sources and headers, only the headers are visible when compiling the submission
+    pub files: Vec<SourceFile>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct BatchEvaluation {
     pub id: u64,
     pub code: String,
+    #[serde(default)]
+    pub files: Vec<SourceFile>,
     pub language: EvaluationLanguage,
     pub testcases: Vec<Testcase>,
     pub time_limit: u32,
     pub memory_limit: u32,
+    // KiB, the evaluator's default is used if missing
+    pub output_limit: Option<u32>,
+    #[serde(default)]
+    pub compile_limits: LimitOverrides,
+    #[serde(default)]
+    pub result_options: ResultOptions,
     pub checker: Option<CheckerData>,
+    #[serde(default)]
+    pub subtasks: Vec<Subtask>,
+    #[serde(default)]
+    pub evaluation_mode: EvaluationMode,
+    // one per supported language, empty if the problem doesn't have a grader
+    #[serde(default)]
+    pub graders: Vec<Grader>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct InteractiveEvaluation {
     pub id: u64,
     pub code: String,
+    #[serde(default)]
+    pub files: Vec<SourceFile>,
     pub language: EvaluationLanguage,
     pub testcases: Vec<Testcase>,
     pub time_limit: u32,
     pub memory_limit: u32,
+    // KiB, the evaluator's default is used if missing
+    pub output_limit: Option<u32>,
+    #[serde(default)]
+    pub compile_limits: LimitOverrides,
+    #[serde(default)]
+    pub result_options: ResultOptions,
     pub checker: CheckerData,
+    #[serde(default)]
+    pub evaluation_mode: EvaluationMode,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -68,6 +210,8 @@ pub enum Evaluation {
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct EvaluationMeta {
     pub output_queue: String,
+    // if set, progress events are pushed here while the evaluation is running
+    pub progress_queue: Option<String>,
     pub evaluation: Evaluation,
 }
 
@@ -86,6 +230,7 @@ pub struct Testcase {
     pub id: String,
     pub input: String,
     pub output: String,
+    pub subtask: Option<u32>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -95,6 +240,6 @@ pub enum SystemMessage {
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub enum Message {
-    BeginEvaluation(EvaluationMeta),
+    BeginEvaluation(Box<EvaluationMeta>),
     System(SystemMessage),
 }
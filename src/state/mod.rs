@@ -1,7 +1,27 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::{Mutex, Notify};
 
+#[derive(Default)]
 pub struct AppState {
     pub used_box_ids: Mutex<HashSet<u8>>,
     pub available_boxes_notify: Notify,
+
+    // raw queue messages of evaluations that haven't published a result yet,
+    //  keyed by the first box id of the evaluation
+    pub unfinished_messages: Mutex<HashMap<u8, String>>,
+    pub shutting_down: AtomicBool,
+}
+
+impl AppState {
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        // wake up anyone waiting for boxes so they can notice the shutdown
+        self.available_boxes_notify.notify_waiters();
+    }
 }
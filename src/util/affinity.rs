@@ -0,0 +1,118 @@
+use crate::environment::Environment;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::{fs, io, mem};
+use tracing::{info, warn};
+
+lazy_static! {
+    // dedicated cores, box ids are mapped onto them in order
+    static ref BOX_CORES: Vec<usize> = dedicated_cores();
+}
+
+fn dedicated_cores() -> Vec<usize> {
+    let available = core_affinity::get_core_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|it| it.id)
+        .collect::<Vec<_>>();
+
+    let candidates = match &Environment::get().cpu_cores {
+        Some(cores) => cores
+            .iter()
+            .copied()
+            .filter(|core| {
+                let is_available = available.contains(core);
+                if !is_available {
+                    warn!("Configured CPU core {core} isn't available, ignoring it");
+                }
+
+                is_available
+            })
+            .collect(),
+        None => available,
+    };
+
+    // hyperthread siblings share execution units, so only the first of them is used
+    let mut taken = HashSet::new();
+
+    candidates
+        .into_iter()
+        .filter(|core| {
+            if taken.contains(core) {
+                return false;
+            }
+
+            taken.extend(thread_siblings(*core));
+            taken.insert(*core);
+
+            true
+        })
+        .collect()
+}
+
+// parses lists like "0,4" or "0-1"
+fn thread_siblings(core: usize) -> Vec<usize> {
+    let Ok(content) = fs::read_to_string(format!(
+        "/sys/devices/system/cpu/cpu{core}/topology/thread_siblings_list"
+    )) else {
+        return vec![];
+    };
+
+    content
+        .trim()
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some((start.parse().ok()?..=end.parse().ok()?).collect()),
+            None => Some(vec![range.parse().ok()?]),
+        })
+        .flatten()
+        .collect()
+}
+
+pub fn core_for_box(box_id: u8) -> Option<usize> {
+    if BOX_CORES.is_empty() {
+        return None;
+    }
+
+    Some(BOX_CORES[box_id as usize % BOX_CORES.len()])
+}
+
+pub fn log_core_assignment() {
+    if BOX_CORES.is_empty() {
+        warn!("No CPU cores available for pinning, boxes will run on any core");
+        return;
+    }
+
+    let max_evaluations = Environment::get().max_evaluations as usize;
+
+    if max_evaluations > BOX_CORES.len() {
+        warn!(
+            "{max_evaluations} boxes but only {} dedicated CPU cores, some boxes will share a core",
+            BOX_CORES.len()
+        );
+    }
+
+    for box_id in 0..Environment::get().max_evaluations {
+        if let Some(core) = core_for_box(box_id) {
+            info!("Box {box_id} is pinned to CPU core {core}");
+        }
+    }
+}
+
+// meant to be called between fork and exec, so it only does the syscall
+pub fn pin_current_process(core: usize) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, all zeroes is an empty set
+    let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+
+    // SAFETY: set is valid and sched_setaffinity only reads it
+    let result = unsafe {
+        libc::CPU_SET(core, &mut cpu_set);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpu_set)
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
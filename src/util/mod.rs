@@ -1,2 +1,3 @@
+pub mod affinity;
 pub mod fd;
 pub mod general;